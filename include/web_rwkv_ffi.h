@@ -7,6 +7,135 @@ struct Sampler
   uintptr_t top_k;
 };
 
+/// Repetition penalties applied to the logits before sampling.
+/// Each sampled token `t` lowers its own logit in later picks by `presence + frequency * count[t]`,
+/// and all counts are multiplied by `decay` after every pick.
+struct Penalty
+{
+  float presence;
+  float frequency;
+  float decay;
+};
+
+/// An additive bias on the logit of one token. A bias of `-INFINITY` bans the token.
+struct LogitBias
+{
+  uint32_t token;
+  float bias;
+};
+
+/// The only layout of `SamplerConfig` understood by this version of the library.
+#define WKTV_SAMPLER_CONFIG_VERSION 1
+
+/// How the weights of a layer are stored on the GPU.
+enum QuantKind {
+  QUANT_KIND_NONE = 0,
+  QUANT_KIND_INT8 = 1,
+  QUANT_KIND_NF4 = 2,
+  QUANT_KIND_SF4 = 3,
+};
+
+/// The quantization of one layer.
+struct LayerQuant {
+  uintptr_t layer;
+  /// One of `enum QuantKind`.
+  uint32_t quant;
+};
+
+/// The only layout of `LoadOptions` understood by this version of the library.
+#define WKTV_LOAD_OPTIONS_VERSION 1
+
+/// How a model file is laid out.
+enum ModelFormat {
+  /// Raw weights in safetensors, quantized while loading.
+  MODEL_FORMAT_SAFE_TENSORS = 0,
+  /// A cbor prefab, already quantized.
+  MODEL_FORMAT_PREFAB = 1,
+};
+
+/// Float type of the activations and of the non-quantized weights.
+enum Precision {
+  PRECISION_F32 = 0,
+  PRECISION_F16 = 1,
+};
+
+/// Which GPU to prefer when no adapter index is given.
+enum PowerPreference {
+  POWER_PREFERENCE_HIGH_PERFORMANCE = 0,
+  POWER_PREFERENCE_LOW_POWER = 1,
+};
+
+/// Everything a loader can be told. `version` must be set to `WKTV_LOAD_OPTIONS_VERSION`.
+struct LoadOptions
+{
+  uint32_t version;
+  const char *path;
+  /// One of `enum ModelFormat`.
+  uint32_t format;
+  /// Quantization of each listed layer; may be null if `quant_len` is 0. Must be empty for prefabs.
+  const struct LayerQuant *quant;
+  uintptr_t quant_len;
+  /// Rescale the activations every `rescale` layers; 0 keeps the model's default. Must be 0 for prefabs.
+  uintptr_t rescale;
+  /// Install the extended hooks of v6 and v7 models, prefabs included; other versions fail with `InvalidArgument`.
+  bool extended;
+  /// One of `enum Precision`.
+  uint32_t precision;
+  uintptr_t num_batch;
+  /// Number of tokens run per dispatch; 0 picks the default of 128.
+  uintptr_t token_chunk_size;
+  /// One of `enum PowerPreference`.
+  uint32_t power_preference;
+  /// Index of the GPU among all adapters found; negative to pick one by `power_preference`.
+  int32_t adapter_index;
+};
+
+/// Which truncation a `SamplerConfig` applies before drawing a token.
+enum SamplerMode {
+  /// Keep the `top_k` most likely tokens, then cut off at cumulative probability `top_p`. Greedy if `top_k <= 1`.
+  SAMPLER_MODE_NUCLEUS = 0,
+  /// Keep tokens at least `min_p` times as likely as the most likely one.
+  SAMPLER_MODE_MIN_P = 1,
+  /// Keep the tokens whose surprise is closest to the entropy, up to cumulative probability `typical_p`.
+  SAMPLER_MODE_TYPICAL = 2,
+  /// Keep tokens with probability at least `top_a` times the square of the highest probability.
+  SAMPLER_MODE_TOP_A = 3,
+  /// Mirostat v2: keep tokens with surprise below `mu`, and steer `mu` so the observed surprise tracks `mirostat_tau`.
+  SAMPLER_MODE_MIROSTAT = 4,
+};
+
+/// Sampling parameters that supersede `Sampler`.
+/// `version` must be set to `WKTV_SAMPLER_CONFIG_VERSION`. Fields not used by `mode` are ignored.
+struct SamplerConfig
+{
+  uint32_t version;
+  /// One of `enum SamplerMode`.
+  uint32_t mode;
+  float temp;
+  float top_p;
+  uintptr_t top_k;
+  float min_p;
+  float typical_p;
+  float top_a;
+  float mirostat_tau;
+  float mirostat_eta;
+};
+
+/// A token and its log-probability.
+struct TokenProb {
+  uint32_t token;
+  float logprob;
+};
+
+/// A sampled token with its log-probability and the most likely alternatives, in descending order. Free with `free_logprobs`.
+struct TokenLogprobs {
+  uint32_t token;
+  float logprob;
+  uintptr_t len;
+  struct TokenProb *top;
+};
+
+/// Logits owned by the library; release with `free_raw`. An empty output has a null `logits`.
 struct ModelOutput {
   uintptr_t len;
   float *logits;
@@ -19,41 +148,261 @@ struct ModelInfoOutput {
   uintptr_t num_emb;
   uintptr_t num_vocab;
   uintptr_t num_head;
+  /// The format the model was loaded from.
+  enum ModelFormat format;
+  /// Float type of the activations. Weights that are not quantized are always stored in f16.
+  enum Precision precision;
+  /// Number of layers stored in Int8; see `get_layer_quant` for which ones.
+  uintptr_t num_int8;
+  /// Number of layers stored in NF4.
+  uintptr_t num_nf4;
+  /// Number of layers stored in SF4.
+  uintptr_t num_sf4;
 };
 
+/// A flattened state. Returned by `get_state` and owned by the library until `free_state`;
+/// passed to `set_state` it stays owned by the caller. An empty state has a null `state`.
 struct StateRaw {
   uintptr_t len;
   float *state;
 };
 
+/// Which predictions a batch slot outputs.
+enum InferOption {
+  /// Only output the prediction for the last token.
+  INFER_OPTION_LAST = 0,
+  /// Output predictions for all tokens.
+  INFER_OPTION_FULL = 1,
+};
+
+/// Input of one batch slot.
+struct InferBatch {
+  const uint32_t *tokens;
+  uintptr_t len;
+  /// One of `enum InferOption`.
+  uint32_t option;
+};
+
+/// Status code returned by the exported functions.
+enum WktvStatus {
+  WKTV_OK = 0,
+  /// The runtime or tokenizer is not loaded, or the handle is null.
+  WKTV_NOT_LOADED = 1,
+  /// The input token array is empty.
+  WKTV_EMPTY_INPUT = 2,
+  /// A file does not exist or cannot be accessed.
+  WKTV_BAD_PATH = 3,
+  /// The model, vocabulary, grammar or state file cannot be parsed, or ends early.
+  WKTV_PARSE = 4,
+  /// The GPU failed to initialize or execute.
+  WKTV_GPU = 5,
+  /// A buffer does not match the expected shape or size.
+  WKTV_SHAPE_MISMATCH = 6,
+  /// A required pointer argument is null.
+  WKTV_NULL_POINTER = 7,
+  /// An argument is out of its valid range.
+  WKTV_INVALID_ARGUMENT = 8,
+  /// The call was stopped through a cancellation token.
+  WKTV_CANCELLED = 9,
+  /// Any other error; see `last_error_message`.
+  WKTV_UNKNOWN = -1,
+};
+
+/// Tokens produced by `tokenize`. Free with `free_tokens`.
+struct TokenOutput {
+  uintptr_t len;
+  uint32_t *data;
+};
+
+/// UTF-8 bytes produced by the text functions. The bytes are not null-terminated. Free with `free_text`.
+struct TextOutput {
+  uintptr_t len;
+  uint8_t *data;
+};
+
+/// Why a generation loop stopped.
+enum FinishReason {
+  /// `max_tokens` tokens were generated.
+  FINISH_REASON_MAX_TOKENS = 0,
+  /// One of the stop tokens was sampled.
+  FINISH_REASON_STOP_TOKEN = 1,
+  /// The output ended with one of the stop sequences.
+  FINISH_REASON_STOP_SEQUENCE = 2,
+  /// The token callback returned `false`.
+  FINISH_REASON_CALLBACK = 3,
+  /// The grammar of the slot was completed.
+  FINISH_REASON_GRAMMAR = 4,
+};
+
+/// Called after each sampled token with its log-probability. Return `false` to stop generating.
+typedef bool (*OnToken)(void *user_data, uint32_t token, float logprob);
+
+/// A token sequence, e.g. a stop sequence.
+struct TokenSequence {
+  const uint32_t *tokens;
+  uintptr_t len;
+};
+
+/// Opaque handle to a loaded runtime.
+typedef struct WktvRuntime WktvRuntime;
+
+/// Opaque handle to a cancellation token.
+typedef struct WktvCancelToken WktvCancelToken;
+
+/// Opaque handle to a loaded tokenizer.
+typedef struct WktvTokenizer WktvTokenizer;
+
+/// Opaque handle to a compiled grammar.
+typedef struct WktvGrammar WktvGrammar;
+
 #ifdef __cplusplus
 extern "C" {
 #endif
 /// Initialize logger and RNG. Call this once before everything.
 void init(uint64_t seed);
 
-/// Set the RNG seed.
+/// Set the RNG seed. Every slot of the global runtime, if loaded, is reseeded from it as well.
 void seed(uint64_t seed);
 
+/// Read the RNG state of a slot. Saving it together with the model state, and restoring both later,
+/// replays sampling on the slot bit-exactly.
+enum WktvStatus get_rng_state(uint64_t *output);
+
+enum WktvStatus get_rng_state_handle(const WktvRuntime *runtime, uint64_t *output);
+
+enum WktvStatus get_rng_state_slot(const WktvRuntime *runtime, uintptr_t slot, uint64_t *output);
+
+/// Set the RNG state of a slot, either to a seed or to a value read by `get_rng_state`.
+enum WktvStatus set_rng_state(uint64_t state);
+
+enum WktvStatus set_rng_state_handle(const WktvRuntime *runtime, uint64_t state);
+
+enum WktvStatus set_rng_state_slot(const WktvRuntime *runtime, uintptr_t slot, uint64_t state);
+
+/// Returns the status of the last call made on this thread.
+enum WktvStatus last_error_code();
+
+/// Returns the error chain of the last failed call made on this thread, or an empty string if it succeeded.
+/// The string is owned by the library and stays valid until the next call on this thread.
+const char *last_error_message();
+
+/// Load the global runtime as described by `options`. Every other loader is a shorthand for this one.
+enum WktvStatus load_with_options(const struct LoadOptions *options);
+
+/// Load a runtime as described by `options` and write its handle into `runtime`.
+enum WktvStatus load_with_options_create(const struct LoadOptions *options, WktvRuntime **runtime);
+
+/// Load the global runtime from the `len` bytes of a safetensors or prefab file; `options->path` is ignored.
+/// The bytes may be freed as soon as the call returns.
+enum WktvStatus load_from_memory(const uint8_t *data, uintptr_t len, const struct LoadOptions *options);
+
+/// Load a runtime from the `len` bytes of a safetensors or prefab file and write its handle into `runtime`.
+enum WktvStatus load_from_memory_create(const uint8_t *data,
+                                        uintptr_t len,
+                                        const struct LoadOptions *options,
+                                        WktvRuntime **runtime);
+
+/// Build a model from the safetensors file at `src` with the quantization and rescaling of `options`,
+/// and write it to `dst` as a prefab. `options->path` is ignored and `options->format` must be safetensors.
+/// `dst` is only replaced once the prefab is completely written; a failed export leaves it untouched.
+enum WktvStatus export_prefab(const char *src, const char *dst, const struct LoadOptions *options);
+
 /// Load a runtime.
 ///
 /// # Safety
 ///
 /// The caller must ensure that `model` is valid.
-void load(const char *model, uintptr_t quant, uintptr_t quant_nf4, uintptr_t quant_sf4, bool fp16);
+enum WktvStatus load(const char *model, uintptr_t quant, uintptr_t quant_nf4, uintptr_t quant_sf4, bool fp16);
+
+enum WktvStatus load_prefab(const char *model, bool fp16);
+
+/// Load a runtime with extended hooks; fails with `WKTV_INVALID_ARGUMENT` for v4 and v5 models, which have none.
+enum WktvStatus load_extended(const char *model, uintptr_t quant, uintptr_t quant_nf4, uintptr_t quant_sf4, bool fp16);
+
+enum WktvStatus load_with_rescale(const char *model, uintptr_t quant, uintptr_t quant_nf4, uintptr_t quant_sf4, uintptr_t rescale, bool fp16);
+
+enum WktvStatus release();
+
+/// Load a runtime and write its handle into `runtime`.
+enum WktvStatus load_create(const char *model, uintptr_t quant, uintptr_t quant_nf4, uintptr_t quant_sf4, bool fp16, WktvRuntime **runtime);
+
+enum WktvStatus load_prefab_create(const char *model, bool fp16, WktvRuntime **runtime);
+
+enum WktvStatus load_extended_create(const char *model, uintptr_t quant, uintptr_t quant_nf4, uintptr_t quant_sf4, bool fp16, WktvRuntime **runtime);
+
+enum WktvStatus load_with_rescale_create(const char *model, uintptr_t quant, uintptr_t quant_nf4, uintptr_t quant_sf4, uintptr_t rescale, bool fp16, WktvRuntime **runtime);
+
+/// Load a runtime with `num_batch` slots that can be inferred in parallel.
+enum WktvStatus load_batch_create(const char *model, uintptr_t quant, uintptr_t quant_nf4, uintptr_t quant_sf4, bool fp16, uintptr_t num_batch, WktvRuntime **runtime);
+
+enum WktvStatus load_prefab_batch_create(const char *model, bool fp16, uintptr_t num_batch, WktvRuntime **runtime);
+
+/// Load a runtime with the quantization of each layer given by `(layer, quant)` pairs.
+/// Layers not listed are not quantized, and later pairs for the same layer override earlier ones.
+enum WktvStatus load_with_quant_map(const char *model, const struct LayerQuant *layers, uintptr_t len, bool fp16);
+
+enum WktvStatus load_with_quant_map_create(const char *model, const struct LayerQuant *layers, uintptr_t len, bool fp16, WktvRuntime **runtime);
+
+/// Load a runtime with the quantization of each layer given by a spec such as "0-3:none,4-20:nf4,21-23:int8".
+/// Layers not listed are not quantized, and later items override earlier ones.
+enum WktvStatus load_with_quant_spec(const char *model, const char *spec, bool fp16);
+
+enum WktvStatus load_with_quant_spec_create(const char *model, const char *spec, bool fp16, WktvRuntime **runtime);
+
+/// Release a runtime handle. The handle must not be used afterwards.
+enum WktvStatus release_handle(WktvRuntime *runtime);
 
-void load_prefab(const char *model, bool fp16);
+/// Create a cancellation token bound to slot 0 of the runtime. The token outlives the runtime and must be freed with `release_cancel_token`.
+enum WktvStatus create_cancel_token(WktvCancelToken **token);
 
-void load_extended(const char *model, uintptr_t quant, uintptr_t quant_nf4, uintptr_t quant_sf4, bool fp16);
+enum WktvStatus create_cancel_token_handle(const WktvRuntime *runtime, WktvCancelToken **token);
 
-void load_with_rescale(const char *model, uintptr_t quant, uintptr_t quant_nf4, uintptr_t quant_sf4, uintptr_t rescale, bool fp16);
+/// Create a cancellation token bound to one batch slot; a batched call is cancelled through any slot it runs tokens on.
+enum WktvStatus create_cancel_token_slot(const WktvRuntime *runtime, uintptr_t slot, WktvCancelToken **token);
 
-void release();
+/// Request cancellation of the call running on the token's slot. Safe to call from any thread.
+/// The call stops before its next chunk of at most `token_chunk_size` tokens and returns `WKTV_CANCELLED`;
+/// a request made while the slot is idle has no effect.
+enum WktvStatus cancel(const WktvCancelToken *token);
+
+enum WktvStatus release_cancel_token(WktvCancelToken *token);
 
 /// Clear the model state.
-void clear_state();
+enum WktvStatus clear_state();
+
+enum WktvStatus clear_state_handle(const WktvRuntime *runtime);
+
+/// Clear the state of one batch slot.
+enum WktvStatus clear_state_slot(const WktvRuntime *runtime, uintptr_t slot);
+
+/// Copy the state of batch slot `src` into batch slot `dst`, e.g. to fork a conversation.
+/// The RNG is copied too, so both slots sample alike until one is reseeded with `set_rng_state_slot`.
+enum WktvStatus copy_state_slot(const WktvRuntime *runtime, uintptr_t src, uintptr_t dst);
+
+/// Set the repetition penalties. They stay in effect across calls; the token counts are reset by `clear_state`.
+enum WktvStatus set_penalty(struct Penalty penalty);
+
+enum WktvStatus set_penalty_handle(const WktvRuntime *runtime, struct Penalty penalty);
+
+enum WktvStatus set_penalty_slot(const WktvRuntime *runtime, uintptr_t slot, struct Penalty penalty);
+
+/// Replace the logit bias applied before the softmax by every sampling call. Pass an empty list to remove all biases.
+enum WktvStatus set_logit_bias(const struct LogitBias *bias, uintptr_t len);
+
+enum WktvStatus set_logit_bias_handle(const WktvRuntime *runtime, const struct LogitBias *bias, uintptr_t len);
+
+enum WktvStatus set_logit_bias_slot(const WktvRuntime *runtime, uintptr_t slot, const struct LogitBias *bias, uintptr_t len);
+
+/// Select the sampling strategy. Once set, the config is used instead of the `Sampler` passed to each call;
+/// pass null to go back to the per-call `Sampler`. Mirostat's `mu` restarts from `2 * tau` here and on `clear_state`.
+enum WktvStatus set_sampler_config(const struct SamplerConfig *config);
+
+enum WktvStatus set_sampler_config_handle(const WktvRuntime *runtime, const struct SamplerConfig *config);
+
+enum WktvStatus set_sampler_config_slot(const WktvRuntime *runtime, uintptr_t slot, const struct SamplerConfig *config);
 
 /// Generate the next token prediction given the input tokens and a sampler.
+/// Returns `0` on failure; check `last_error_code` to tell it apart from token `0`.
 ///
 /// # Safety
 ///
@@ -62,31 +411,371 @@ uint32_t infer(const uint32_t *tokens,
                uintptr_t len,
                struct Sampler sampler);
 
+enum WktvStatus infer_handle(const WktvRuntime *runtime,
+                             const uint32_t *tokens,
+                             uintptr_t len,
+                             struct Sampler sampler,
+                             uint32_t *output);
+
+/// Like `infer`, but also add `bias` to the logits of this call only, on top of the slot's own bias.
+/// Returns `0` on failure; a bias that bans every token fails with `WKTV_INVALID_ARGUMENT`.
+uint32_t infer_with_bias(const uint32_t *tokens,
+                         uintptr_t len,
+                         struct Sampler sampler,
+                         const struct LogitBias *bias,
+                         uintptr_t bias_len);
+
+enum WktvStatus infer_with_bias_handle(const WktvRuntime *runtime,
+                                       const uint32_t *tokens,
+                                       uintptr_t len,
+                                       struct Sampler sampler,
+                                       const struct LogitBias *bias,
+                                       uintptr_t bias_len,
+                                       uint32_t *output);
+
+/// Prefill the input tokens and keep sampling in a single call.
+/// Stops after `max_tokens` tokens, when any of `stop_tokens` is sampled, or when the output ends with any of `stop_sequences`;
+/// either stop list may be null. The generated tokens, including the one that triggered the stop, are written into `output`
+/// (free with `free_tokens`) and fed back into the state. On failure or cancellation, the tokens generated so far are still written.
+enum WktvStatus generate(const uint32_t *tokens,
+                         uintptr_t len,
+                         struct Sampler sampler,
+                         uintptr_t max_tokens,
+                         const uint32_t *stop_tokens,
+                         uintptr_t stop_count,
+                         const struct TokenSequence *stop_sequences,
+                         uintptr_t stop_sequence_count,
+                         struct TokenOutput *output,
+                         enum FinishReason *reason);
+
+enum WktvStatus generate_handle(const WktvRuntime *runtime,
+                                const uint32_t *tokens,
+                                uintptr_t len,
+                                struct Sampler sampler,
+                                uintptr_t max_tokens,
+                                const uint32_t *stop_tokens,
+                                uintptr_t stop_count,
+                                const struct TokenSequence *stop_sequences,
+                                uintptr_t stop_sequence_count,
+                                struct TokenOutput *output,
+                                enum FinishReason *reason);
+
+/// Like `generate`, but also add `bias` to the logits of every token sampled by this call.
+enum WktvStatus generate_with_bias(const uint32_t *tokens,
+                                   uintptr_t len,
+                                   struct Sampler sampler,
+                                   const struct LogitBias *bias,
+                                   uintptr_t bias_len,
+                                   uintptr_t max_tokens,
+                                   const uint32_t *stop_tokens,
+                                   uintptr_t stop_count,
+                                   const struct TokenSequence *stop_sequences,
+                                   uintptr_t stop_sequence_count,
+                                   struct TokenOutput *output,
+                                   enum FinishReason *reason);
+
+enum WktvStatus generate_with_bias_handle(const WktvRuntime *runtime,
+                                          const uint32_t *tokens,
+                                          uintptr_t len,
+                                          struct Sampler sampler,
+                                          const struct LogitBias *bias,
+                                          uintptr_t bias_len,
+                                          uintptr_t max_tokens,
+                                          const uint32_t *stop_tokens,
+                                          uintptr_t stop_count,
+                                          const struct TokenSequence *stop_sequences,
+                                          uintptr_t stop_sequence_count,
+                                          struct TokenOutput *output,
+                                          enum FinishReason *reason);
+
+/// Like `generate`, but hands each token to `on_token` as soon as it is sampled.
+/// `user_data` is passed through untouched; the callback runs on the calling thread.
+enum WktvStatus generate_stream(const uint32_t *tokens,
+                                uintptr_t len,
+                                struct Sampler sampler,
+                                uintptr_t max_tokens,
+                                const uint32_t *stop_tokens,
+                                uintptr_t stop_count,
+                                const struct TokenSequence *stop_sequences,
+                                uintptr_t stop_sequence_count,
+                                OnToken on_token,
+                                void *user_data,
+                                enum FinishReason *reason);
+
+enum WktvStatus generate_stream_handle(const WktvRuntime *runtime,
+                                       const uint32_t *tokens,
+                                       uintptr_t len,
+                                       struct Sampler sampler,
+                                       uintptr_t max_tokens,
+                                       const uint32_t *stop_tokens,
+                                       uintptr_t stop_count,
+                                       const struct TokenSequence *stop_sequences,
+                                       uintptr_t stop_sequence_count,
+                                       OnToken on_token,
+                                       void *user_data,
+                                       enum FinishReason *reason);
+
+/// Like `generate_stream`, but also add `bias` to the logits of every token sampled by this call.
+enum WktvStatus generate_stream_with_bias(const uint32_t *tokens,
+                                          uintptr_t len,
+                                          struct Sampler sampler,
+                                          const struct LogitBias *bias,
+                                          uintptr_t bias_len,
+                                          uintptr_t max_tokens,
+                                          const uint32_t *stop_tokens,
+                                          uintptr_t stop_count,
+                                          const struct TokenSequence *stop_sequences,
+                                          uintptr_t stop_sequence_count,
+                                          OnToken on_token,
+                                          void *user_data,
+                                          enum FinishReason *reason);
+
+enum WktvStatus generate_stream_with_bias_handle(const WktvRuntime *runtime,
+                                                 const uint32_t *tokens,
+                                                 uintptr_t len,
+                                                 struct Sampler sampler,
+                                                 const struct LogitBias *bias,
+                                                 uintptr_t bias_len,
+                                                 uintptr_t max_tokens,
+                                                 const uint32_t *stop_tokens,
+                                                 uintptr_t stop_count,
+                                                 const struct TokenSequence *stop_sequences,
+                                                 uintptr_t stop_sequence_count,
+                                                 OnToken on_token,
+                                                 void *user_data,
+                                                 enum FinishReason *reason);
+
+/// Like `infer`, but also report the log-probability of the token and the `top_n` most likely tokens.
+/// The probabilities are taken after penalties, bias and grammar masking, but before truncation and temperature.
+enum WktvStatus infer_logprobs(const uint32_t *tokens, uintptr_t len, struct Sampler sampler, uintptr_t top_n, struct TokenLogprobs *output);
+
+enum WktvStatus infer_logprobs_handle(const WktvRuntime *runtime,
+                                      const uint32_t *tokens,
+                                      uintptr_t len,
+                                      struct Sampler sampler,
+                                      uintptr_t top_n,
+                                      struct TokenLogprobs *output);
+
+void free_logprobs(struct TokenLogprobs output);
+
 /// Delete the model output vector created by the infer functions.
 void free_raw(struct ModelOutput output);
 
 /// Compute the model's raw output (next token prediction only) given the input tokens.
+/// Returns an empty output on failure; check `last_error_code` for the reason.
 ///
 /// # Safety
 ///
 /// The caller must ensure that `tokens` is valid and `len` does not exceed the actual length of `tokens`.
 struct ModelOutput infer_raw_last(const uint32_t *tokens, uintptr_t len);
 
+enum WktvStatus infer_raw_last_handle(const WktvRuntime *runtime, const uint32_t *tokens, uintptr_t len, struct ModelOutput *output);
+
+/// Compute the model's raw output (next token prediction only) into a caller-provided buffer of at least `num_vocab` floats.
+/// A shorter buffer returns `WKTV_SHAPE_MISMATCH` before the state is touched.
+enum WktvStatus infer_raw_last_into(const uint32_t *tokens, uintptr_t len, float *output, uintptr_t output_len);
+
+enum WktvStatus infer_raw_last_into_handle(const WktvRuntime *runtime, const uint32_t *tokens, uintptr_t len, float *output, uintptr_t output_len);
+
+/// Compute the model's raw output (predictions of all tokens) into a caller-provided buffer of at least `len * num_vocab` floats.
+/// Chunks are written as they are computed, without intermediate allocations.
+enum WktvStatus infer_raw_full_into(const uint32_t *tokens, uintptr_t len, float *output, uintptr_t output_len);
+
+enum WktvStatus infer_raw_full_into_handle(const WktvRuntime *runtime, const uint32_t *tokens, uintptr_t len, float *output, uintptr_t output_len);
+
+/// Query the number of floats the raw infer functions output for `len` tokens.
+/// `option` is one of `enum InferOption`.
+enum WktvStatus get_output_size(uintptr_t len, uint32_t option, uintptr_t *output);
+
+enum WktvStatus get_output_size_handle(const WktvRuntime *runtime, uintptr_t len, uint32_t option, uintptr_t *output);
+
 /// Compute the model's raw output (predictions of all tokens) given the input tokens.
+/// Returns an empty output on failure; check `last_error_code` for the reason.
 ///
 /// # Safety
 ///
 /// The caller must ensure that `tokens` is valid and `len` does not exceed the actual length of `tokens`.
-struct ModelOutput infer_raw_all(const uint32_t *tokens, uintptr_t len);
+struct ModelOutput infer_raw_full(const uint32_t *tokens, uintptr_t len);
+
+enum WktvStatus infer_raw_full_handle(const WktvRuntime *runtime, const uint32_t *tokens, uintptr_t len, struct ModelOutput *output);
+
+/// Advance every batch slot in the same dispatches, writing the raw output of slot `i` into `outputs[i]`.
+/// Slots with no tokens keep their state and yield an empty output.
+/// Both `inputs` and `outputs` must have exactly one element per slot.
+enum WktvStatus infer_batch(const WktvRuntime *runtime, const struct InferBatch *inputs, uintptr_t num_batch, struct ModelOutput *outputs);
 
 struct ModelInfoOutput get_model_info();
 
+enum WktvStatus get_model_info_handle(const WktvRuntime *runtime, struct ModelInfoOutput *output);
+
+/// Write how each layer of the global model is stored into `output`, which must hold `num_layer` values.
+enum WktvStatus get_layer_quant(enum QuantKind *output, uintptr_t len);
+
+enum WktvStatus get_layer_quant_handle(const WktvRuntime *runtime, enum QuantKind *output, uintptr_t len);
+
 struct StateRaw get_state();
 
-void set_state(struct StateRaw state);
+enum WktvStatus get_state_handle(const WktvRuntime *runtime, struct StateRaw *output);
+
+enum WktvStatus get_state_slot(const WktvRuntime *runtime, uintptr_t slot, struct StateRaw *output);
+
+/// Set the model state. Returns `WKTV_SHAPE_MISMATCH` unless `state.len` equals the length of `get_state`.
+enum WktvStatus set_state(struct StateRaw state);
+
+enum WktvStatus set_state_handle(const WktvRuntime *runtime, struct StateRaw state);
+
+enum WktvStatus set_state_slot(const WktvRuntime *runtime, uintptr_t slot, struct StateRaw state);
+
+/// Set the model state from half-precision values, given as their IEEE 754 binary16 bits.
+enum WktvStatus set_state_f16(const uint16_t *data, uintptr_t len);
+
+enum WktvStatus set_state_f16_handle(const WktvRuntime *runtime, const uint16_t *data, uintptr_t len);
+
+enum WktvStatus set_state_f16_slot(const WktvRuntime *runtime, uintptr_t slot, const uint16_t *data, uintptr_t len);
+
+/// Query the number of floats in the state of one slot.
+enum WktvStatus get_state_size(uintptr_t *output);
+
+enum WktvStatus get_state_size_handle(const WktvRuntime *runtime, uintptr_t *output);
+
+/// Copy the state of a slot into a caller-provided buffer of at least `get_state_size` floats.
+enum WktvStatus get_state_into(float *output, uintptr_t output_len);
+
+enum WktvStatus get_state_into_handle(const WktvRuntime *runtime, float *output, uintptr_t output_len);
+
+enum WktvStatus get_state_into_slot(const WktvRuntime *runtime, uintptr_t slot, float *output, uintptr_t output_len);
 
 void free_state(struct StateRaw state);
 
+/// Load the initial state from a state-tuning safetensors file (v5, v6 and v7 models only) and clear every slot to it;
+/// `clear_state` resets to it from then on. Pass null to go back to the all-zero initial state.
+enum WktvStatus load_initial_state(const char *path);
+
+enum WktvStatus load_initial_state_handle(const WktvRuntime *runtime, const char *path);
+
+/// Save the state of a slot to a safetensors file, together with the model's version, dimensions,
+/// a fingerprint of its weights and the slot's RNG state.
+enum WktvStatus save_state(const char *path);
+
+enum WktvStatus save_state_handle(const WktvRuntime *runtime, const char *path);
+
+enum WktvStatus save_state_slot(const WktvRuntime *runtime, uintptr_t slot, const char *path);
+
+/// Load a file written by `save_state` into a slot and restore its RNG state.
+/// Returns `WKTV_SHAPE_MISMATCH` without touching the slot if the file comes from another model.
+enum WktvStatus load_state(const char *path);
+
+enum WktvStatus load_state_handle(const WktvRuntime *runtime, const char *path);
+
+enum WktvStatus load_state_slot(const WktvRuntime *runtime, uintptr_t slot, const char *path);
+
+/// Load the global tokenizer from a vocabulary file, e.g. `rwkv_vocab_v20230424.json`.
+enum WktvStatus load_tokenizer(const char *path);
+
+/// Load a tokenizer and write its handle into `tokenizer`.
+enum WktvStatus load_tokenizer_create(const char *path, WktvTokenizer **tokenizer);
+
+/// Release a tokenizer handle. The handle must not be used afterwards.
+enum WktvStatus release_tokenizer_handle(WktvTokenizer *tokenizer);
+
+/// Encode a null-terminated UTF-8 string into tokens.
+enum WktvStatus tokenize(const char *text, struct TokenOutput *output);
+
+enum WktvStatus tokenize_handle(const WktvTokenizer *tokenizer, const char *text, struct TokenOutput *output);
+
+/// Decode tokens into raw bytes, which may end in the middle of a UTF-8 sequence.
+enum WktvStatus detokenize(const uint32_t *tokens, uintptr_t len, struct TextOutput *output);
+
+enum WktvStatus detokenize_handle(const WktvTokenizer *tokenizer, const uint32_t *tokens, uintptr_t len, struct TextOutput *output);
+
+void free_tokens(struct TokenOutput output);
+
+void free_text(struct TextOutput output);
+
+/// Encode the text and predict the next token, which is not fed back into the state.
+/// Writes the token into `token` and its bytes into `output`, along with any held back by an earlier text call;
+/// they may end in the middle of a character.
+enum WktvStatus infer_text(const char *text, struct Sampler sampler, uint32_t *token, struct TextOutput *output);
+
+enum WktvStatus infer_text_handle(const WktvRuntime *runtime,
+                                  const WktvTokenizer *tokenizer,
+                                  const char *text,
+                                  struct Sampler sampler,
+                                  uint32_t *token,
+                                  struct TextOutput *output);
+
+/// Like `infer_text`, but also add `bias` to the logits of this call only.
+enum WktvStatus infer_text_with_bias(const char *text,
+                                     struct Sampler sampler,
+                                     const struct LogitBias *bias,
+                                     uintptr_t bias_len,
+                                     uint32_t *token,
+                                     struct TextOutput *output);
+
+enum WktvStatus infer_text_with_bias_handle(const WktvRuntime *runtime,
+                                            const WktvTokenizer *tokenizer,
+                                            const char *text,
+                                            struct Sampler sampler,
+                                            const struct LogitBias *bias,
+                                            uintptr_t bias_len,
+                                            uint32_t *token,
+                                            struct TextOutput *output);
+
+/// Continue the text with up to `max_tokens` sampled tokens, all of which are fed back into the state.
+/// On failure or cancellation, the text generated so far is still written.
+enum WktvStatus generate_text(const char *text, struct Sampler sampler, uintptr_t max_tokens, struct TextOutput *output);
+
+enum WktvStatus generate_text_handle(const WktvRuntime *runtime,
+                                     const WktvTokenizer *tokenizer,
+                                     const char *text,
+                                     struct Sampler sampler,
+                                     uintptr_t max_tokens,
+                                     struct TextOutput *output);
+
+/// Like `generate_text`, but also add `bias` to the logits of every token sampled by this call.
+enum WktvStatus generate_text_with_bias(const char *text,
+                                        struct Sampler sampler,
+                                        const struct LogitBias *bias,
+                                        uintptr_t bias_len,
+                                        uintptr_t max_tokens,
+                                        struct TextOutput *output);
+
+enum WktvStatus generate_text_with_bias_handle(const WktvRuntime *runtime,
+                                               const WktvTokenizer *tokenizer,
+                                               const char *text,
+                                               struct Sampler sampler,
+                                               const struct LogitBias *bias,
+                                               uintptr_t bias_len,
+                                               uintptr_t max_tokens,
+                                               struct TextOutput *output);
+
+/// Compile a GBNF grammar whose start rule is `root`.
+enum WktvStatus compile_grammar(const char *gbnf, WktvGrammar **grammar);
+
+/// Compile a JSON Schema into a grammar of the JSON documents it accepts.
+enum WktvStatus compile_json_schema(const char *schema, WktvGrammar **grammar);
+
+/// Release a grammar handle. Runtimes the grammar is attached to keep their own reference.
+enum WktvStatus release_grammar(WktvGrammar *grammar);
+
+/// Constrain sampling to the grammar, using the global tokenizer. Pass null to remove the grammar.
+enum WktvStatus set_grammar(const WktvGrammar *grammar);
+
+enum WktvStatus set_grammar_handle(const WktvRuntime *runtime, const WktvTokenizer *tokenizer, const WktvGrammar *grammar);
+
+/// Constrain every token sampled on one slot to keep the output a prefix of a sentence of the grammar.
+/// Once the output is complete, the end-of-text token `0` is also allowed.
+/// The match restarts here and on `clear_state`.
+enum WktvStatus set_grammar_slot(const WktvRuntime *runtime, const WktvTokenizer *tokenizer, uintptr_t slot, const WktvGrammar *grammar);
+
+/// Cache the state after each chunk of every run of at least `min_tokens` tokens through any slot, within `budget` bytes.
+/// Later runs continuing from a cached sequence restore it and only run the remaining tokens.
+/// Least recently used states are evicted first. Any previous cache is dropped; a `budget` of 0 disables caching.
+/// Tokens are only tracked while the cache is on; a slot that ran while it was off is cached again after `clear_state`.
+enum WktvStatus set_prefix_cache(uintptr_t budget, uintptr_t min_tokens);
+
+enum WktvStatus set_prefix_cache_handle(const WktvRuntime *runtime, uintptr_t budget, uintptr_t min_tokens);
+
 #ifdef __cplusplus
 } // extern "C"
-#endif
\ No newline at end of file
+#endif
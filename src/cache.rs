@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+
+use web_rwkv::tensor::TensorCpu;
+
+/// Hash of one more token appended to a sequence with hash `hash`.
+fn roll(hash: u64, token: u32) -> u64 {
+    (hash ^ token as u64).wrapping_mul(0x100000001b3)
+}
+
+/// The tokens fed into a slot since its state was cleared, with a rolling hash over them.
+#[derive(Debug, Clone)]
+pub struct Sequence {
+    tokens: Vec<u32>,
+    hash: u64,
+}
+
+impl Default for Sequence {
+    fn default() -> Self {
+        Self {
+            tokens: vec![],
+            hash: 0xcbf29ce484222325,
+        }
+    }
+}
+
+impl Sequence {
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn extend(&mut self, tokens: &[u32]) {
+        self.hash = tokens.iter().fold(self.hash, |hash, &token| roll(hash, token));
+        self.tokens.extend_from_slice(tokens);
+    }
+}
+
+/// A snapshot of the state right after a sequence, and the logits of its last token if they were computed.
+struct Entry {
+    tokens: Vec<u32>,
+    state: TensorCpu<f32>,
+    output: Option<Vec<f32>>,
+    last_used: u64,
+}
+
+impl Entry {
+    fn size(&self) -> usize {
+        (self.tokens.len() + self.state.len() + self.output.as_ref().map_or(0, Vec::len)) * 4
+    }
+}
+
+/// Snapshots of the state after previously seen token sequences, evicted least-recently-used first to stay within a byte budget.
+pub struct PrefixCache {
+    budget: usize,
+    min_tokens: usize,
+    size: usize,
+    clock: u64,
+    entries: HashMap<u64, Entry>,
+}
+
+/// A cache hit: the number of tokens it covers, with the state after them.
+///
+/// The logits of the last covered token are only present if the hit covers all the tokens looked up.
+pub struct Hit {
+    pub len: usize,
+    pub state: TensorCpu<f32>,
+    pub output: Option<Vec<f32>>,
+}
+
+impl PrefixCache {
+    pub fn new(budget: usize, min_tokens: usize) -> Self {
+        Self {
+            budget,
+            min_tokens,
+            size: 0,
+            clock: 0,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Find the longest cached sequence that extends `sequence` by a prefix of `tokens`.
+    ///
+    /// A snapshot without logits is only used if it leaves at least one token to run, which then yields them.
+    pub fn lookup(&mut self, sequence: &Sequence, tokens: &[u32]) -> Option<Hit> {
+        let hashes = tokens
+            .iter()
+            .scan(sequence.hash, |hash, &token| {
+                *hash = roll(*hash, token);
+                Some(*hash)
+            })
+            .collect::<Vec<_>>();
+        for (index, hash) in hashes.into_iter().enumerate().rev() {
+            let Some(entry) = self.entries.get_mut(&hash) else {
+                continue;
+            };
+            let len = sequence.len() + index + 1;
+            let (head, tail) = entry.tokens.split_at(sequence.len().min(entry.tokens.len()));
+            if entry.tokens.len() != len || head != sequence.tokens || tail != &tokens[..=index] {
+                continue;
+            }
+            let covers = index + 1 == tokens.len();
+            if covers && entry.output.is_none() {
+                continue;
+            }
+            self.clock += 1;
+            entry.last_used = self.clock;
+            return Some(Hit {
+                len: index + 1,
+                state: entry.state.clone(),
+                output: entry.output.clone().filter(|_| covers),
+            });
+        }
+        None
+    }
+
+    /// Whether a prefill of `len` tokens is long enough to be worth snapshots.
+    /// Shorter runs, such as the single tokens of generation, are never snapshotted.
+    pub fn wants(&self, len: usize) -> bool {
+        len >= self.min_tokens
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.size = 0;
+    }
+
+    /// Snapshot the state after `sequence`. A snapshot without logits keeps those of an earlier one of the same sequence.
+    pub fn insert(&mut self, sequence: &Sequence, state: TensorCpu<f32>, output: Option<Vec<f32>>) {
+        let output = match output {
+            Some(output) => Some(output),
+            None => self
+                .entries
+                .get(&sequence.hash)
+                .filter(|entry| entry.tokens == sequence.tokens)
+                .and_then(|entry| entry.output.clone()),
+        };
+        self.clock += 1;
+        let entry = Entry {
+            tokens: sequence.tokens.clone(),
+            state,
+            output,
+            last_used: self.clock,
+        };
+        if entry.size() > self.budget {
+            return;
+        }
+        self.size += entry.size();
+        if let Some(old) = self.entries.insert(sequence.hash, entry) {
+            self.size -= old.size();
+        }
+        while self.size > self.budget {
+            let Some((&hash, _)) = self.entries.iter().min_by_key(|(_, entry)| entry.last_used) else {
+                break;
+            };
+            let entry = self.entries.remove(&hash).unwrap();
+            self.size -= entry.size();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use web_rwkv::tensor::TensorInit;
+
+    use super::*;
+
+    fn sequence(tokens: &[u32]) -> Sequence {
+        let mut sequence = Sequence::default();
+        sequence.extend(tokens);
+        sequence
+    }
+
+    fn state(value: f32) -> TensorCpu<f32> {
+        TensorCpu::from_data_1d(vec![value; 4])
+    }
+
+    /// Size in bytes of an entry of `len` tokens with a test state and no logits.
+    fn size(len: usize) -> usize {
+        (len + 4) * 4
+    }
+
+    #[test]
+    fn lookup_finds_longest_continuation() {
+        let mut cache = PrefixCache::new(usize::MAX, 1);
+        cache.insert(&sequence(&[1, 2]), state(2.0), None);
+        cache.insert(&sequence(&[1, 2, 3, 4]), state(4.0), None);
+
+        let hit = cache.lookup(&sequence(&[1]), &[2, 3, 4, 5]).unwrap();
+        assert_eq!(hit.len, 3);
+        assert_eq!(hit.state.to_vec(), vec![4.0; 4]);
+        assert!(hit.output.is_none());
+
+        let hit = cache.lookup(&Sequence::default(), &[1, 2, 7]).unwrap();
+        assert_eq!(hit.len, 2);
+        assert!(cache.lookup(&Sequence::default(), &[2, 3]).is_none());
+        assert!(cache.lookup(&sequence(&[9]), &[2, 3, 4, 5]).is_none());
+    }
+
+    #[test]
+    fn lookup_covering_all_tokens_needs_logits() {
+        let mut cache = PrefixCache::new(usize::MAX, 1);
+        cache.insert(&sequence(&[1]), state(1.0), Some(vec![0.5]));
+        cache.insert(&sequence(&[1, 2]), state(2.0), None);
+
+        // The snapshot after [1, 2] has no logits, so the one after [1] is used to leave a token to run.
+        let hit = cache.lookup(&Sequence::default(), &[1, 2]).unwrap();
+        assert_eq!(hit.len, 1);
+        assert!(hit.output.is_none());
+
+        let hit = cache.lookup(&Sequence::default(), &[1]).unwrap();
+        assert_eq!(hit.output, Some(vec![0.5]));
+
+        // A snapshot without logits keeps those already stored for the same sequence.
+        cache.insert(&sequence(&[1]), state(1.0), None);
+        let hit = cache.lookup(&Sequence::default(), &[1]).unwrap();
+        assert_eq!(hit.output, Some(vec![0.5]));
+    }
+
+    #[test]
+    fn insert_evicts_least_recently_used() {
+        let mut cache = PrefixCache::new(size(1) * 2, 1);
+        cache.insert(&sequence(&[1]), state(1.0), None);
+        cache.insert(&sequence(&[2]), state(2.0), None);
+        assert!(cache.lookup(&Sequence::default(), &[1, 0]).is_some());
+
+        cache.insert(&sequence(&[3]), state(3.0), None);
+        assert_eq!(cache.size, size(1) * 2);
+        assert!(cache.lookup(&Sequence::default(), &[1, 0]).is_some());
+        assert!(cache.lookup(&Sequence::default(), &[2, 0]).is_none());
+        assert!(cache.lookup(&Sequence::default(), &[3, 0]).is_some());
+    }
+
+    #[test]
+    fn insert_skips_entries_over_budget() {
+        let mut cache = PrefixCache::new(size(1), 1);
+        cache.insert(&sequence(&[1, 2]), state(1.0), None);
+        assert_eq!(cache.size, 0);
+        assert!(cache.lookup(&Sequence::default(), &[1, 2, 3]).is_none());
+
+        cache.insert(&sequence(&[1]), state(1.0), None);
+        cache.insert(&sequence(&[1]), state(1.0), None);
+        assert_eq!(cache.size, size(1));
+    }
+}
@@ -0,0 +1,112 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Tracks the call running on each slot of a runtime, so that a cancellation only stops the call it was meant for.
+///
+/// Every call gets a fresh id. Cancelling a slot marks the id of the call running on it at that moment,
+/// so a request made while the slot is idle is dropped instead of hitting the next call.
+#[derive(Debug)]
+pub struct Calls {
+    /// Id of the last call started on any slot.
+    last: AtomicU64,
+    /// Id of the call running on each slot, or 0 while it is idle.
+    running: Vec<AtomicU64>,
+    /// Id of the last call cancelled on each slot.
+    cancelled: Vec<AtomicU64>,
+}
+
+/// Marks the slots of a call as running until dropped.
+pub struct Call<'a> {
+    calls: &'a Calls,
+    id: u64,
+    /// The slots this call claimed, as opposed to the ones it joined an enclosing call on.
+    slots: Vec<usize>,
+}
+
+impl Calls {
+    pub fn new(num_batch: usize) -> Self {
+        Self {
+            last: AtomicU64::new(0),
+            running: (0..num_batch).map(|_| AtomicU64::new(0)).collect(),
+            cancelled: (0..num_batch).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    /// Start a call on the slots. A slot already running a call is left to it, so nested calls share one id.
+    pub fn begin(&self, slots: impl IntoIterator<Item = usize>) -> Call<'_> {
+        let id = self.last.fetch_add(1, Ordering::AcqRel) + 1;
+        let slots = slots
+            .into_iter()
+            .filter(|&slot| {
+                self.running[slot]
+                    .compare_exchange(0, id, Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok()
+            })
+            .collect();
+        Call { calls: self, id, slots }
+    }
+
+    /// Whether the call running on any of the slots has been cancelled.
+    pub fn is_cancelled(&self, slots: impl IntoIterator<Item = usize>) -> bool {
+        slots.into_iter().any(|slot| {
+            let id = self.running[slot].load(Ordering::Acquire);
+            id != 0 && self.cancelled[slot].load(Ordering::Acquire) == id
+        })
+    }
+
+    /// Cancel the call running on the slot, if any.
+    pub fn cancel(&self, slot: usize) {
+        let id = self.running[slot].load(Ordering::Acquire);
+        if id != 0 {
+            self.cancelled[slot].store(id, Ordering::Release);
+        }
+    }
+}
+
+impl Drop for Call<'_> {
+    fn drop(&mut self) {
+        for &slot in &self.slots {
+            let _ = self.calls.running[slot].compare_exchange(self.id, 0, Ordering::AcqRel, Ordering::Acquire);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancel_while_idle_is_dropped() {
+        let calls = Calls::new(1);
+        calls.cancel(0);
+        let _call = calls.begin([0]);
+        assert!(!calls.is_cancelled([0]));
+    }
+
+    #[test]
+    fn cancel_stops_only_the_running_call() {
+        let calls = Calls::new(2);
+        {
+            let _call = calls.begin([0]);
+            let _other = calls.begin([1]);
+            calls.cancel(0);
+            assert!(calls.is_cancelled([0]));
+            assert!(!calls.is_cancelled([1]));
+            assert!(calls.is_cancelled([0, 1]));
+        }
+        let _call = calls.begin([0]);
+        assert!(!calls.is_cancelled([0]));
+    }
+
+    #[test]
+    fn nested_calls_share_the_cancellation() {
+        let calls = Calls::new(1);
+        let _outer = calls.begin([0]);
+        calls.cancel(0);
+        {
+            let _inner = calls.begin([0]);
+            assert!(calls.is_cancelled([0]));
+        }
+        // Ending the inner call leaves the outer one running.
+        assert!(calls.is_cancelled([0]));
+    }
+}
@@ -0,0 +1,200 @@
+use std::{
+    cell::RefCell,
+    ffi::{c_char, CString},
+    io::ErrorKind,
+};
+
+use safetensors::SafeTensorError;
+use web_rwkv::{
+    context::ContextError,
+    runtime::{loader::LoaderError, RuntimeError},
+    tensor::{TensorError, TensorErrorKind},
+    tokenizer::TokenizerError,
+};
+
+/// Status code returned by the exported functions.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WktvStatus {
+    /// The call succeeded.
+    Ok = 0,
+    /// The runtime or tokenizer is not loaded, or the handle is null.
+    NotLoaded = 1,
+    /// The input token array is empty.
+    EmptyInput = 2,
+    /// A file does not exist or cannot be accessed.
+    BadPath = 3,
+    /// The model, vocabulary, grammar or state file cannot be parsed, or ends early.
+    Parse = 4,
+    /// The GPU failed to initialize or execute.
+    Gpu = 5,
+    /// A buffer or saved state does not match the expected shape, size or model.
+    ShapeMismatch = 6,
+    /// A required pointer argument is null.
+    NullPointer = 7,
+    /// An argument is out of its valid range.
+    InvalidArgument = 8,
+    /// The call was stopped through a cancellation token.
+    Cancelled = 9,
+    /// Any other error; see `last_error_message`.
+    Unknown = -1,
+}
+
+/// Errors raised by the FFI layer itself.
+#[derive(Debug)]
+pub enum FfiError {
+    NotLoaded,
+    TokenizerNotLoaded,
+    NullHandle,
+    NullPointer(&'static str),
+    EmptyInput,
+    InvalidPrefab(String),
+    InvalidArgument(&'static str),
+    BatchMismatch { expected: usize, actual: usize },
+    SlotOutOfRange { slot: usize, num_batch: usize },
+    StateSizeMismatch { expected: usize, actual: usize },
+    BufferTooSmall { required: usize, actual: usize },
+    Cancelled,
+    InvalidGrammar(String),
+    GrammarRejected(u32),
+    GrammarDeadEnd,
+    InvalidStateFile(String),
+    InvalidQuantSpec(String),
+    IncompatibleState(String),
+}
+
+impl std::fmt::Display for FfiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FfiError::NotLoaded => write!(f, "runtime not loaded"),
+            FfiError::TokenizerNotLoaded => write!(f, "tokenizer not loaded"),
+            FfiError::NullHandle => write!(f, "handle is null"),
+            FfiError::NullPointer(name) => write!(f, "`{name}` is null"),
+            FfiError::EmptyInput => write!(f, "input cannot be empty"),
+            FfiError::InvalidPrefab(err) => write!(f, "failed to parse prefab: {err}"),
+            FfiError::InvalidArgument(err) => write!(f, "{err}"),
+            FfiError::BatchMismatch { expected, actual } => {
+                write!(f, "expected {expected} batch slots, got {actual}")
+            }
+            FfiError::SlotOutOfRange { slot, num_batch } => {
+                write!(f, "slot {slot} out of range of {num_batch} batch slots")
+            }
+            FfiError::StateSizeMismatch { expected, actual } => {
+                write!(f, "expected a state of {expected} values, got {actual}")
+            }
+            FfiError::BufferTooSmall { required, actual } => {
+                write!(f, "buffer of {actual} values is too small, {required} are required")
+            }
+            FfiError::Cancelled => write!(f, "cancelled"),
+            FfiError::InvalidGrammar(err) => write!(f, "failed to compile grammar: {err}"),
+            FfiError::GrammarRejected(token) => write!(f, "token {token} is rejected by the grammar"),
+            FfiError::GrammarDeadEnd => write!(f, "no token can continue the grammar"),
+            FfiError::InvalidStateFile(err) => write!(f, "failed to parse state file: {err}"),
+            FfiError::InvalidQuantSpec(item) => write!(f, "invalid quant spec item `{item}`"),
+            FfiError::IncompatibleState(err) => write!(f, "incompatible state: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for FfiError {}
+
+impl FfiError {
+    fn status(&self) -> WktvStatus {
+        match self {
+            FfiError::NotLoaded | FfiError::TokenizerNotLoaded | FfiError::NullHandle => WktvStatus::NotLoaded,
+            FfiError::NullPointer(_) => WktvStatus::NullPointer,
+            FfiError::EmptyInput => WktvStatus::EmptyInput,
+            FfiError::InvalidPrefab(_) => WktvStatus::Parse,
+            FfiError::InvalidArgument(_) => WktvStatus::InvalidArgument,
+            FfiError::BatchMismatch { .. } => WktvStatus::ShapeMismatch,
+            FfiError::SlotOutOfRange { .. } => WktvStatus::InvalidArgument,
+            FfiError::StateSizeMismatch { .. } | FfiError::BufferTooSmall { .. } => WktvStatus::ShapeMismatch,
+            FfiError::Cancelled => WktvStatus::Cancelled,
+            FfiError::InvalidGrammar(_) => WktvStatus::Parse,
+            FfiError::GrammarRejected(_) | FfiError::GrammarDeadEnd => WktvStatus::InvalidArgument,
+            FfiError::InvalidStateFile(_) => WktvStatus::Parse,
+            FfiError::InvalidQuantSpec(_) => WktvStatus::InvalidArgument,
+            FfiError::IncompatibleState(_) => WktvStatus::ShapeMismatch,
+        }
+    }
+}
+
+impl From<&anyhow::Error> for WktvStatus {
+    fn from(err: &anyhow::Error) -> Self {
+        for cause in err.chain() {
+            if let Some(err) = cause.downcast_ref::<FfiError>() {
+                return err.status();
+            }
+            if let Some(err) = cause.downcast_ref::<std::io::Error>() {
+                return match err.kind() {
+                    ErrorKind::NotFound | ErrorKind::PermissionDenied => WktvStatus::BadPath,
+                    ErrorKind::UnexpectedEof | ErrorKind::InvalidData => WktvStatus::Parse,
+                    _ => WktvStatus::Unknown,
+                };
+            }
+            if cause.is::<SafeTensorError>() || cause.is::<LoaderError>() {
+                return WktvStatus::Parse;
+            }
+            if let Some(err) = cause.downcast_ref::<TensorError>() {
+                return match err.error {
+                    TensorErrorKind::Size(..)
+                    | TensorErrorKind::Batch(..)
+                    | TensorErrorKind::Shape(..)
+                    | TensorErrorKind::BatchOutOfRange { .. }
+                    | TensorErrorKind::SliceOutOfRange { .. } => WktvStatus::ShapeMismatch,
+                    _ => WktvStatus::Gpu,
+                };
+            }
+            if let Some(err) = cause.downcast_ref::<TokenizerError>() {
+                return match err {
+                    TokenizerError::FailedToParseVocabulary(_) => WktvStatus::Parse,
+                    _ => WktvStatus::InvalidArgument,
+                };
+            }
+        }
+        match err
+            .chain()
+            .any(|cause| cause.is::<RuntimeError>() || cause.is::<ContextError>())
+        {
+            true => WktvStatus::Gpu,
+            false => WktvStatus::Unknown,
+        }
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<(WktvStatus, CString)> = RefCell::new((WktvStatus::Ok, CString::default()));
+}
+
+/// Record the outcome of an exported call in the thread-local error slot, and turn it into a status code.
+pub fn report(result: anyhow::Result<()>) -> WktvStatus {
+    let (status, message) = match result {
+        Ok(()) => (WktvStatus::Ok, CString::default()),
+        Err(err) => {
+            let status = WktvStatus::from(&err);
+            // A cancellation is a stop the caller asked for, not a failure.
+            match status {
+                WktvStatus::Cancelled => log::debug!("{err:#}"),
+                _ => log::error!("{err:#}"),
+            }
+            let message = format!("{err:#}").replace('\0', " ");
+            (status, CString::new(message).unwrap_or_default())
+        }
+    };
+    LAST_ERROR.with_borrow_mut(|last| *last = (status, message));
+    status
+}
+
+/// Returns the status of the last call made on this thread.
+#[no_mangle]
+pub extern "C" fn last_error_code() -> WktvStatus {
+    LAST_ERROR.with_borrow(|(status, _)| *status)
+}
+
+/// Returns the error chain of the last failed call made on this thread, or an empty string if it succeeded.
+///
+/// The returned string is owned by the library and stays valid until the next call on this thread.
+#[no_mangle]
+pub extern "C" fn last_error_message() -> *const c_char {
+    LAST_ERROR.with_borrow(|(_, message)| message.as_ptr())
+}
@@ -0,0 +1,813 @@
+use std::{
+    collections::HashMap,
+    ffi::c_char,
+    sync::Arc,
+};
+
+use anyhow::{bail, Result};
+use itertools::Itertools;
+
+use crate::{
+    error::{report, FfiError, WktvStatus},
+    schema::schema_to_gbnf,
+    tokenizer::text_from_raw,
+    write_output,
+};
+
+/// One symbol of a grammar alternative.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Element {
+    /// A single code point within (or, if negated, outside) the inclusive ranges.
+    Chars { ranges: Vec<(u32, u32)>, negated: bool },
+    /// A reference to another rule.
+    Rule(usize),
+}
+
+impl Element {
+    fn char(c: char) -> Self {
+        Element::Chars {
+            ranges: vec![(c as u32, c as u32)],
+            negated: false,
+        }
+    }
+
+    /// Whether some code point in `lo..=hi` matches this class.
+    fn intersects(ranges: &[(u32, u32)], negated: bool, lo: u32, hi: u32) -> bool {
+        if !negated {
+            return ranges.iter().any(|&(start, end)| start <= hi && lo <= end);
+        }
+        let mut cursor = lo;
+        for &(start, end) in ranges.iter().sorted() {
+            if start > cursor {
+                return true;
+            }
+            cursor = cursor.max(end.saturating_add(1));
+            if cursor > hi {
+                return false;
+            }
+        }
+        cursor <= hi
+    }
+}
+
+/// A context-free grammar over Unicode code points, compiled from GBNF.
+#[derive(Debug, Clone)]
+pub struct Grammar {
+    rules: Vec<Vec<Vec<Element>>>,
+    root: usize,
+}
+
+/// Where a stack frame is inside a rule: the next element of alternative `alt` to match is `index`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct Position {
+    rule: u32,
+    alt: u32,
+    index: u32,
+}
+
+type Stack = Vec<Position>;
+
+/// How far a sequence has been matched against a [`Grammar`].
+///
+/// Every possible parse is tracked as a stack of positions; a stack is empty once the root rule is complete.
+#[derive(Debug, Clone)]
+pub struct GrammarState {
+    stacks: Arc<Vec<Stack>>,
+    /// Bits gathered so far of a code point split across bytes, and the number of bytes still missing.
+    partial: Option<(u32, u32)>,
+    /// The end-of-text token was accepted.
+    ended: bool,
+}
+
+impl Grammar {
+    pub fn from_gbnf(source: &str) -> Result<Self> {
+        Parser::new(source).parse()
+    }
+
+    pub fn from_json_schema(schema: &str) -> Result<Self> {
+        let schema: serde_json::Value =
+            serde_json::from_str(schema).map_err(|err| FfiError::InvalidGrammar(err.to_string()))?;
+        Self::from_gbnf(&schema_to_gbnf(&schema)?)
+    }
+
+    fn element(&self, position: Position) -> Option<&Element> {
+        self.rules[position.rule as usize][position.alt as usize].get(position.index as usize)
+    }
+
+    /// Resolve rule references on top of the stack until it waits for a code point or is empty.
+    fn expand(&self, mut stack: Stack, output: &mut Vec<Stack>) {
+        loop {
+            let Some(&top) = stack.last() else {
+                output.push(stack);
+                return;
+            };
+            match self.element(top) {
+                None => {
+                    stack.pop();
+                }
+                Some(Element::Chars { .. }) => {
+                    output.push(stack);
+                    return;
+                }
+                Some(&Element::Rule(rule)) => {
+                    stack.last_mut().unwrap().index += 1;
+                    // Frames with nothing left after the reference are done, so drop them before descending.
+                    // Otherwise every round of a repetition, which recurses in tail position, would deepen the stack.
+                    while stack.last().is_some_and(|&top| self.element(top).is_none()) {
+                        stack.pop();
+                    }
+                    for alt in 0..self.rules[rule].len() {
+                        let mut stack = stack.clone();
+                        stack.push(Position {
+                            rule: rule as u32,
+                            alt: alt as u32,
+                            index: 0,
+                        });
+                        self.expand(stack, output);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    pub fn start(&self) -> GrammarState {
+        let mut stacks = vec![];
+        for alt in 0..self.rules[self.root].len() {
+            let position = Position {
+                rule: self.root as u32,
+                alt: alt as u32,
+                index: 0,
+            };
+            self.expand(vec![position], &mut stacks);
+        }
+        stacks.sort();
+        stacks.dedup();
+        GrammarState {
+            stacks: Arc::new(stacks),
+            partial: None,
+            ended: false,
+        }
+    }
+
+    fn accept_char(&self, stacks: &[Stack], c: u32) -> Vec<Stack> {
+        let mut output = vec![];
+        for stack in stacks {
+            let Some(&top) = stack.last() else {
+                continue;
+            };
+            let Some(Element::Chars { ranges, negated }) = self.element(top) else {
+                continue;
+            };
+            if Element::intersects(ranges, *negated, c, c) {
+                let mut stack = stack.clone();
+                stack.last_mut().unwrap().index += 1;
+                self.expand(stack, &mut output);
+            }
+        }
+        output.sort();
+        output.dedup();
+        output
+    }
+
+    /// Whether some stack can still match a code point within `lo..=hi`.
+    fn can_accept(&self, stacks: &[Stack], lo: u32, hi: u32) -> bool {
+        stacks.iter().filter_map(|stack| stack.last()).any(|&top| match self.element(top) {
+            Some(Element::Chars { ranges, negated }) => Element::intersects(ranges, *negated, lo, hi),
+            _ => false,
+        })
+    }
+
+    /// Feed one byte of UTF-8, returning the next state if the grammar allows it.
+    fn accept_byte(&self, state: &GrammarState, byte: u8) -> Option<GrammarState> {
+        if state.ended {
+            return None;
+        }
+        let (bits, missing) = match state.partial {
+            None => match byte {
+                0x00..=0x7f => (byte as u32, 0),
+                0xc0..=0xdf => ((byte & 0x1f) as u32, 1),
+                0xe0..=0xef => ((byte & 0x0f) as u32, 2),
+                0xf0..=0xf7 => ((byte & 0x07) as u32, 3),
+                _ => return None,
+            },
+            Some((bits, missing)) => match byte {
+                0x80..=0xbf => ((bits << 6) | (byte & 0x3f) as u32, missing - 1),
+                _ => return None,
+            },
+        };
+        if missing > 0 {
+            let lo = bits << (6 * missing);
+            let hi = lo | ((1 << (6 * missing)) - 1);
+            return self.can_accept(&state.stacks, lo, hi).then(|| GrammarState {
+                stacks: state.stacks.clone(),
+                partial: Some((bits, missing)),
+                ended: false,
+            });
+        }
+        let stacks = self.accept_char(&state.stacks, bits);
+        (!stacks.is_empty()).then(|| GrammarState {
+            stacks: Arc::new(stacks),
+            partial: None,
+            ended: false,
+        })
+    }
+
+    /// Whether the text so far is a complete sentence of the grammar.
+    pub fn is_accepting(&self, state: &GrammarState) -> bool {
+        state.partial.is_none() && state.stacks.iter().any(|stack| stack.is_empty())
+    }
+
+    /// Whether nothing can follow the text so far.
+    pub fn is_done(&self, state: &GrammarState) -> bool {
+        state.ended || (state.partial.is_none() && state.stacks.iter().all(|stack| stack.is_empty()))
+    }
+
+    /// Mark the tokens that keep the text a prefix of some sentence of the grammar.
+    ///
+    /// The end-of-text token `0` is allowed once the text is a complete sentence.
+    pub fn allowed(&self, state: &GrammarState, vocab: &Vocab, num_vocab: usize) -> Vec<bool> {
+        let mut allowed = vec![false; num_vocab];
+        if num_vocab > 0 && self.is_accepting(state) {
+            allowed[0] = true;
+        }
+        let mut queue = vec![(0, state.clone())];
+        while let Some((node, state)) = queue.pop() {
+            for &(byte, child) in &vocab.nodes[node].children {
+                let Some(state) = self.accept_byte(&state, byte) else {
+                    continue;
+                };
+                for &token in &vocab.nodes[child].tokens {
+                    if let Some(allowed) = allowed.get_mut(token as usize) {
+                        *allowed = true;
+                    }
+                }
+                queue.push((child, state));
+            }
+        }
+        allowed
+    }
+
+    /// Advance the state past a sampled token.
+    pub fn advance(&self, state: &GrammarState, token: u32, vocab: &Vocab) -> Result<GrammarState> {
+        if token == 0 && self.is_accepting(state) {
+            return Ok(GrammarState {
+                ended: true,
+                ..state.clone()
+            });
+        }
+        let bytes = vocab.tokens.get(token as usize).filter(|bytes| !bytes.is_empty());
+        let Some(bytes) = bytes else {
+            bail!(FfiError::GrammarRejected(token));
+        };
+        let mut state = state.clone();
+        for &byte in bytes {
+            let Some(next) = self.accept_byte(&state, byte) else {
+                bail!(FfiError::GrammarRejected(token));
+            };
+            state = next;
+        }
+        Ok(state)
+    }
+}
+
+#[derive(Debug, Default)]
+struct VocabNode {
+    children: Vec<(u8, usize)>,
+    tokens: Vec<u32>,
+}
+
+/// The bytes of every token arranged as a trie, so that tokens sharing a prefix are checked against a grammar only once.
+#[derive(Debug)]
+pub struct Vocab {
+    tokens: Vec<Vec<u8>>,
+    nodes: Vec<VocabNode>,
+}
+
+impl Vocab {
+    pub fn new(tokens: &[Vec<u8>]) -> Self {
+        let mut nodes = vec![VocabNode::default()];
+        for (token, bytes) in tokens.iter().enumerate().filter(|(_, bytes)| !bytes.is_empty()) {
+            let mut node = 0;
+            for &byte in bytes {
+                node = match nodes[node].children.iter().find(|&&(x, _)| x == byte) {
+                    Some(&(_, child)) => child,
+                    None => {
+                        nodes.push(VocabNode::default());
+                        let child = nodes.len() - 1;
+                        nodes[node].children.push((byte, child));
+                        child
+                    }
+                };
+            }
+            nodes[node].tokens.push(token as u32);
+        }
+        Self {
+            tokens: tokens.to_vec(),
+            nodes,
+        }
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    names: HashMap<String, usize>,
+    rules: Vec<Option<Vec<Vec<Element>>>>,
+    rule_names: Vec<String>,
+}
+
+impl Parser {
+    fn new(source: &str) -> Self {
+        Self {
+            chars: source.chars().collect(),
+            pos: 0,
+            names: HashMap::new(),
+            rules: vec![],
+            rule_names: vec![],
+        }
+    }
+
+    fn error(&self, message: impl std::fmt::Display) -> anyhow::Error {
+        let line = self.chars[..self.pos.min(self.chars.len())]
+            .iter()
+            .filter(|&&c| c == '\n')
+            .count();
+        FfiError::InvalidGrammar(format!("line {}: {message}", line + 1)).into()
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<char> {
+        let c = self.peek().ok_or_else(|| self.error("unexpected end of grammar"))?;
+        self.pos += 1;
+        Ok(c)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        match self.next()? {
+            c if c == expected => Ok(()),
+            c => Err(self.error(format!("expected `{expected}`, found `{c}`"))),
+        }
+    }
+
+    /// Skip whitespace, newlines and `#` comments.
+    fn skip_space(&mut self) {
+        while let Some(c) = self.peek() {
+            match c {
+                '#' => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                c if c.is_whitespace() => self.pos += 1,
+                _ => break,
+            }
+        }
+    }
+
+    fn parse_name(&mut self) -> Option<String> {
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            self.pos += 1;
+        }
+        (self.pos > start).then(|| self.chars[start..self.pos].iter().collect())
+    }
+
+    /// Whether the input continues with `name ::=`, which starts the next rule and ends the current one.
+    fn at_rule_start(&mut self) -> bool {
+        let start = self.pos;
+        let found = self.parse_name().is_some() && {
+            self.skip_space();
+            self.chars[self.pos..].starts_with(&[':', ':', '='])
+        };
+        self.pos = start;
+        found
+    }
+
+    fn rule_id(&mut self, name: &str) -> usize {
+        if let Some(&id) = self.names.get(name) {
+            return id;
+        }
+        self.rules.push(None);
+        self.rule_names.push(name.to_string());
+        self.names.insert(name.to_string(), self.rules.len() - 1);
+        self.rules.len() - 1
+    }
+
+    fn synthetic(&mut self, base: &str, alts: Vec<Vec<Element>>) -> usize {
+        let name = format!("{base}_{}", self.rules.len());
+        let id = self.rule_id(&name);
+        self.rules[id] = Some(alts);
+        id
+    }
+
+    fn parse(mut self) -> Result<Grammar> {
+        loop {
+            self.skip_space();
+            if self.peek().is_none() {
+                break;
+            }
+            let name = self.parse_name().ok_or_else(|| self.error("expected rule name"))?;
+            self.skip_space();
+            for c in "::=".chars() {
+                self.expect(c)?;
+            }
+            let alts = self.parse_alternates(&name)?;
+            let id = self.rule_id(&name);
+            if self.rules[id].replace(alts).is_some() {
+                return Err(self.error(format!("rule `{name}` is defined twice")));
+            }
+        }
+
+        let Some(&root) = self.names.get("root") else {
+            bail!(FfiError::InvalidGrammar("missing `root` rule".into()));
+        };
+        let mut rules = Vec::with_capacity(self.rules.len());
+        for (rule, name) in self.rules.into_iter().zip(&self.rule_names) {
+            match rule {
+                Some(rule) => rules.push(rule),
+                None => bail!(FfiError::InvalidGrammar(format!("undefined rule `{name}`"))),
+            }
+        }
+        let grammar = Grammar { rules, root };
+        if let Some(rule) = grammar.left_recursion() {
+            let name = &self.rule_names[rule];
+            bail!(FfiError::InvalidGrammar(format!("rule `{name}` is left-recursive")));
+        }
+        Ok(grammar)
+    }
+
+    fn parse_alternates(&mut self, name: &str) -> Result<Vec<Vec<Element>>> {
+        let mut alts = vec![self.parse_sequence(name)?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            alts.push(self.parse_sequence(name)?);
+        }
+        Ok(alts)
+    }
+
+    fn parse_sequence(&mut self, name: &str) -> Result<Vec<Element>> {
+        let mut sequence = vec![];
+        loop {
+            self.skip_space();
+            match self.peek() {
+                None | Some('|') | Some(')') => break,
+                _ if self.at_rule_start() => break,
+                _ => {}
+            }
+            let mut elements = match self.next()? {
+                '"' => {
+                    let mut elements = vec![];
+                    while self.peek() != Some('"') {
+                        elements.push(Element::char(self.parse_char()?));
+                    }
+                    self.pos += 1;
+                    elements
+                }
+                '[' => {
+                    let negated = self.peek() == Some('^');
+                    if negated {
+                        self.pos += 1;
+                    }
+                    let mut ranges = vec![];
+                    while self.peek() != Some(']') {
+                        let start = self.parse_char()? as u32;
+                        let end = match self.chars.get(self.pos..self.pos + 2) {
+                            Some(&['-', c]) if c != ']' => {
+                                self.pos += 1;
+                                self.parse_char()? as u32
+                            }
+                            _ => start,
+                        };
+                        ranges.push((start, end));
+                    }
+                    self.pos += 1;
+                    vec![Element::Chars { ranges, negated }]
+                }
+                '(' => {
+                    let alts = self.parse_alternates(name)?;
+                    self.expect(')')?;
+                    vec![Element::Rule(self.synthetic(name, alts))]
+                }
+                '.' => vec![Element::Chars {
+                    ranges: vec![],
+                    negated: true,
+                }],
+                c if c.is_ascii_alphanumeric() || c == '_' || c == '-' => {
+                    self.pos -= 1;
+                    let reference = self.parse_name().unwrap();
+                    vec![Element::Rule(self.rule_id(&reference))]
+                }
+                c => return Err(self.error(format!("unexpected `{c}`"))),
+            };
+
+            let repeat = match self.peek() {
+                Some('*') => Some((0, None)),
+                Some('+') => Some((1, None)),
+                Some('?') => Some((0, Some(1))),
+                Some('{') => Some(self.parse_braces()?),
+                _ => None,
+            };
+            if let Some((min, max)) = repeat {
+                self.pos += 1;
+                let element = match elements.len() {
+                    1 => elements.pop().unwrap(),
+                    _ => Element::Rule(self.synthetic(name, vec![elements])),
+                };
+                elements = self.repeat(name, element, min, max);
+            }
+            sequence.append(&mut elements);
+        }
+        Ok(sequence)
+    }
+
+    /// Read `{m}`, `{m,}` or `{m,n}`.
+    fn parse_braces(&mut self) -> Result<(usize, Option<usize>)> {
+        let start = self.pos;
+        let end = self.chars[start..]
+            .iter()
+            .position(|&c| c == '}')
+            .ok_or_else(|| self.error("unclosed `{`"))?;
+        let body: String = self.chars[start + 1..start + end].iter().collect();
+        let parse = |x: &str| {
+            x.trim()
+                .parse::<usize>()
+                .map_err(|_| self.error(format!("invalid repetition `{{{body}}}`")))
+        };
+        let (min, max) = match body.split_once(',') {
+            None => {
+                let count = parse(&body)?;
+                (count, Some(count))
+            }
+            Some((min, max)) if max.trim().is_empty() => (parse(min)?, None),
+            Some((min, max)) => (parse(min)?, Some(parse(max)?)),
+        };
+        if max.is_some_and(|max| max < min) {
+            return Err(self.error(format!("invalid repetition `{{{body}}}`")));
+        }
+        self.pos = start + end;
+        Ok((min, max))
+    }
+
+    /// Lower `element{min,max}` into `min` copies followed by a chain of optional or recursive rules.
+    fn repeat(&mut self, name: &str, element: Element, min: usize, max: Option<usize>) -> Vec<Element> {
+        let mut elements = vec![element.clone(); min];
+        match max {
+            None => {
+                let id = self.rule_id(&format!("{name}_{}", self.rules.len()));
+                self.rules[id] = Some(vec![vec![element, Element::Rule(id)], vec![]]);
+                elements.push(Element::Rule(id));
+            }
+            Some(max) => {
+                let mut tail = None;
+                for _ in min..max {
+                    let mut alt = vec![element.clone()];
+                    alt.extend(tail.map(Element::Rule));
+                    tail = Some(self.synthetic(name, vec![alt, vec![]]));
+                }
+                elements.extend(tail.map(Element::Rule));
+            }
+        }
+        elements
+    }
+
+    fn parse_char(&mut self) -> Result<char> {
+        let c = self.next()?;
+        if c != '\\' {
+            return Ok(c);
+        }
+        let hex = |parser: &mut Self, len: usize| -> Result<char> {
+            let digits: String = (0..len).map(|_| parser.next()).try_collect()?;
+            u32::from_str_radix(&digits, 16)
+                .ok()
+                .and_then(char::from_u32)
+                .ok_or_else(|| parser.error(format!("invalid escape `{digits}`")))
+        };
+        match self.next()? {
+            'n' => Ok('\n'),
+            'r' => Ok('\r'),
+            't' => Ok('\t'),
+            'x' => hex(self, 2),
+            'u' => hex(self, 4),
+            'U' => hex(self, 8),
+            c => Ok(c),
+        }
+    }
+}
+
+impl Grammar {
+    /// Find a rule that can reach itself without consuming any input, which the matcher cannot expand.
+    fn left_recursion(&self) -> Option<usize> {
+        let mut nullable = vec![false; self.rules.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (rule, alts) in self.rules.iter().enumerate() {
+                if nullable[rule] {
+                    continue;
+                }
+                let empty = alts.iter().any(|alt| {
+                    alt.iter()
+                        .all(|element| matches!(element, &Element::Rule(rule) if nullable[rule]))
+                });
+                if empty {
+                    nullable[rule] = true;
+                    changed = true;
+                }
+            }
+        }
+
+        let leftmost = |rule: usize| {
+            let mut reached = vec![];
+            for alt in &self.rules[rule] {
+                for element in alt {
+                    match *element {
+                        Element::Rule(next) => {
+                            reached.push(next);
+                            if !nullable[next] {
+                                break;
+                            }
+                        }
+                        Element::Chars { .. } => break,
+                    }
+                }
+            }
+            reached
+        };
+        (0..self.rules.len()).find(|&start| {
+            let mut visited = vec![false; self.rules.len()];
+            let mut queue = leftmost(start);
+            while let Some(rule) = queue.pop() {
+                if rule == start {
+                    return true;
+                }
+                if !std::mem::replace(&mut visited[rule], true) {
+                    queue.extend(leftmost(rule));
+                }
+            }
+            false
+        })
+    }
+}
+
+/// A compiled grammar.
+///
+/// This is opaque to C; handles to it are created by `compile_grammar` or `compile_json_schema` and freed by `release_grammar`.
+pub struct WktvGrammar(pub Arc<Grammar>);
+
+/// Compile a GBNF grammar whose start rule is `root`, and write its handle into `grammar`.
+///
+/// # Safety
+///
+/// The caller must ensure that `gbnf` is a valid C string and `grammar` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn compile_grammar(gbnf: *const c_char, grammar: *mut *mut WktvGrammar) -> WktvStatus {
+    report((|| {
+        let gbnf = unsafe { text_from_raw(gbnf) }?;
+        let gbnf = std::str::from_utf8(gbnf).map_err(|err| FfiError::InvalidGrammar(err.to_string()))?;
+        let compiled = Grammar::from_gbnf(gbnf)?;
+        let handle = Box::into_raw(Box::new(WktvGrammar(Arc::new(compiled))));
+        unsafe { write_output(grammar, handle) }
+    })())
+}
+
+/// Compile a JSON Schema into a grammar of the JSON documents it accepts, and write its handle into `grammar`.
+///
+/// # Safety
+///
+/// The caller must ensure that `schema` is a valid C string and `grammar` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn compile_json_schema(schema: *const c_char, grammar: *mut *mut WktvGrammar) -> WktvStatus {
+    report((|| {
+        let schema = unsafe { text_from_raw(schema) }?;
+        let schema = std::str::from_utf8(schema).map_err(|err| FfiError::InvalidGrammar(err.to_string()))?;
+        let compiled = Grammar::from_json_schema(schema)?;
+        let handle = Box::into_raw(Box::new(WktvGrammar(Arc::new(compiled))));
+        unsafe { write_output(grammar, handle) }
+    })())
+}
+
+/// Release a grammar handle. Runtimes the grammar is attached to keep their own reference.
+///
+/// # Safety
+///
+/// The caller must ensure that `grammar` is a handle returned by `compile_grammar` or `compile_json_schema` and not yet released.
+#[no_mangle]
+pub unsafe extern "C" fn release_grammar(grammar: *mut WktvGrammar) -> WktvStatus {
+    report((|| {
+        if grammar.is_null() {
+            bail!(FfiError::NullHandle);
+        }
+        let _ = unsafe { Box::from_raw(grammar) };
+        Ok(())
+    })())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed the text one byte at a time, returning the state after it if the grammar allows it.
+    fn feed(grammar: &Grammar, text: &str) -> Option<GrammarState> {
+        text.bytes()
+            .try_fold(grammar.start(), |state, byte| grammar.accept_byte(&state, byte))
+    }
+
+    fn accepts(grammar: &Grammar, text: &str) -> bool {
+        feed(grammar, text).is_some_and(|state| grammar.is_accepting(&state))
+    }
+
+    #[test]
+    fn repetition() {
+        let grammar = Grammar::from_gbnf(r#"root ::= "a"{2,3} ( "b" | "c" )* "!"?"#).unwrap();
+        assert!(accepts(&grammar, "aa"));
+        assert!(accepts(&grammar, "aaabcb!"));
+        assert!(!accepts(&grammar, "a"));
+        assert!(feed(&grammar, "a").is_some());
+        assert!(feed(&grammar, "aaaa").is_none());
+        assert!(feed(&grammar, "aab!b").is_none());
+        assert!(feed(&grammar, "aad").is_none());
+    }
+
+    #[test]
+    fn multibyte_characters() {
+        let grammar = Grammar::from_gbnf(r#"root ::= [α-ω]+"#).unwrap();
+        assert!(accepts(&grammar, "αβγ"));
+        assert!(feed(&grammar, "αa").is_none());
+        // A partial character is allowed as long as some completion matches.
+        let state = grammar.accept_byte(&grammar.start(), "β".as_bytes()[0]).unwrap();
+        assert!(!grammar.is_accepting(&state));
+    }
+
+    #[test]
+    fn left_recursion_is_rejected() {
+        for gbnf in [
+            r#"root ::= root "a" | "a""#,
+            "root ::= item\nitem ::= empty root \"a\"\nempty ::= \"\"",
+        ] {
+            let err = Grammar::from_gbnf(gbnf).unwrap_err();
+            assert!(err.to_string().contains("left-recursive"), "{err}");
+        }
+        assert!(Grammar::from_gbnf(r#"root ::= "a" root | "a""#).is_ok());
+    }
+
+    #[test]
+    fn invalid_grammars_are_rejected() {
+        for gbnf in [
+            r#"item ::= "a""#,
+            r#"root ::= item"#,
+            r#"root ::= "a"{3,2}"#,
+            "root ::= \"a\"\nroot ::= \"b\"",
+        ] {
+            assert!(Grammar::from_gbnf(gbnf).is_err(), "{gbnf}");
+        }
+    }
+
+    #[test]
+    fn repetition_does_not_grow_the_stack() {
+        let grammar = Grammar::from_gbnf(r#"root ::= "\"" ( [a-z] | "\\" [nt] ){0,5000} "\"" [a-z]*"#).unwrap();
+        let depth = |state: &GrammarState| state.stacks.iter().map(Vec::len).max().unwrap_or_default();
+
+        let mut state = feed(&grammar, "\"").unwrap();
+        let start = depth(&state);
+        for _ in 0..2000 {
+            state = grammar.accept_byte(&state, b'x').unwrap();
+            assert!(depth(&state) <= start + 1);
+        }
+        state = grammar.accept_byte(&state, b'"').unwrap();
+        for _ in 0..2000 {
+            state = grammar.accept_byte(&state, b'y').unwrap();
+            assert!(depth(&state) <= start + 1);
+        }
+        assert!(grammar.is_accepting(&state));
+    }
+
+    #[test]
+    fn allowed_masks_the_vocab() {
+        let grammar = Grammar::from_gbnf(r#"root ::= "ab" | "ac""#).unwrap();
+        let tokens = ["", "a", "ab", "b", "c", "abc"].map(|token| token.as_bytes().to_vec());
+        let vocab = Vocab::new(&tokens);
+
+        let state = grammar.start();
+        let allowed = grammar.allowed(&state, &vocab, tokens.len());
+        assert_eq!(allowed, [false, true, true, false, false, false]);
+
+        let state = grammar.advance(&state, 1, &vocab).unwrap();
+        let allowed = grammar.allowed(&state, &vocab, tokens.len());
+        assert_eq!(allowed, [false, false, false, true, true, false]);
+        assert!(grammar.advance(&state, 1, &vocab).is_err());
+
+        let state = grammar.advance(&state, 4, &vocab).unwrap();
+        let allowed = grammar.allowed(&state, &vocab, tokens.len());
+        assert_eq!(allowed, [true, false, false, false, false, false]);
+        let state = grammar.advance(&state, 0, &vocab).unwrap();
+        assert!(grammar.is_done(&state));
+    }
+}
@@ -1,12 +1,20 @@
+//! C bindings for web-rwkv.
+//!
+//! Enumerations that C writes into a struct or passes by value are declared as `u32` and converted with `TryFrom`,
+//! since a value outside a Rust enum is undefined behaviour; unknown values fail with `InvalidArgument`.
+
 use std::{
-    ffi::{c_char, CStr},
+    collections::HashMap,
+    ffi::{c_char, c_void, CStr},
     path::Path,
-    sync::{Arc, RwLock},
+    sync::{
+        Arc, Mutex, RwLock,
+    },
 };
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use half::f16;
-use serde::{de::DeserializeSeed, Deserialize};
+use serde::{de::DeserializeSeed, Deserialize, Serialize};
 use itertools::Itertools;
 use memmap2::Mmap;
 use safetensors::SafeTensors;
@@ -14,32 +22,128 @@ use tokio::fs::File;
 use web_rwkv::{
     context::{Context, ContextBuilder, InstanceExt},
     runtime::{
-        infer::{Rnn, RnnInput, RnnInputBatch, RnnOption, Token},
+        infer::{Rnn, RnnInfo, RnnInput, RnnInputBatch, RnnOption, RnnOutput, Token},
         loader::Loader,
         model::{
-            ContextAutoLimits, ModelBuilder, ModelInfo, ModelVersion, Quant,
+            ContextAutoLimits, ModelBuilder, ModelInfo, ModelVersion,
             State, Bundle
         },
         softmax::softmax_one,
-        v4, v5, v6, v7, TokioRuntime,
+        v4, v5, v6, v7, Dispatcher, Job, TokioRuntime,
     },
     num::Float,
-    tensor::{ops::TensorOp, serialization::Seed},
+    tensor::{ops::TensorOp, serialization::Seed, TensorCpu, TensorInit},
     wgpu,
 };
+use cache::{PrefixCache, Sequence};
+use cancel::Calls;
+use error::{report, FfiError, WktvStatus};
+use grammar::{Grammar, GrammarState, Vocab, WktvGrammar};
 use ops::TensorOpExt;
-
+use options::{LoadOptions, ModelFormat, Precision, RuntimeOptions};
+use quant::{matrix_quant, parse_quant_spec, quant_from_pairs, LayerQuant, QuantKind, QuantMap};
+use sampler::SamplerConfig;
+use state::StateHeader;
+use tokenizer::{global_tokenizer, text_from_raw, tokenizer_from_handle, TextOutput, TokenOutput, Utf8Buffer, WktvTokenizer};
+
+mod cache;
+mod cancel;
+mod error;
+mod grammar;
 mod ops;
+mod options;
+mod quant;
+mod sampler;
+mod schema;
+mod state;
+mod tokenizer;
 
 static RUNTIME: RwLock<Option<WktvRuntime>> = RwLock::new(None);
 
+/// A loaded model together with its state and the runtimes driving it.
+///
+/// This is opaque to C; handles to it are created by the `*_create` loaders and freed by `release_handle`.
 #[derive(Clone)]
-struct WktvRuntime {
+pub struct WktvRuntime {
     runtime: TokioRuntime<Rnn>,
     info: ModelInfo,
     state: Arc<dyn State + Sync + Send + 'static>,
     context: Context,
     tokio: Arc<tokio::runtime::Runtime>,
+    slots: Arc<Mutex<Vec<SlotState>>>,
+    /// The call running on each slot, for cancellation tokens to stop.
+    calls: Arc<Calls>,
+    /// Identifies the weights, so that saved states are only loaded into the model they came from.
+    fingerprint: u64,
+    /// The state slots are cleared to; zeros unless a state-tuning file is loaded.
+    initial: Arc<RwLock<Option<TensorCpu<f32>>>>,
+    /// Number of tokens run per dispatch.
+    token_chunk_size: usize,
+    /// States after previously run prompts, shared by all slots; `None` while disabled.
+    cache: Arc<Mutex<Option<PrefixCache>>>,
+    format: ModelFormat,
+    /// Whether activations are computed in f16 rather than f32.
+    fp16: bool,
+    /// How the weights of each layer are stored, as built from safetensors or read from a prefab.
+    quant: Arc<[QuantKind]>,
+}
+
+/// A sampled token together with how likely it and its best alternatives were.
+struct Sampled {
+    token: u32,
+    logprob: f32,
+    top: Vec<TokenProb>,
+}
+
+/// Host-side bookkeeping of one batch slot that lives alongside its GPU state.
+#[derive(Debug, Default, Clone)]
+struct SlotState {
+    penalty: Penalty,
+    /// Overrides the [`Sampler`] passed to each call when set.
+    sampler: Option<SamplerConfig>,
+    /// Added to the logits of the given tokens before sampling.
+    bias: HashMap<u32, f32>,
+    /// Masks out the tokens that would break the grammar.
+    grammar: Option<(Arc<Grammar>, Arc<Vocab>)>,
+    /// Draws the random numbers of sampling, so that a slot can be replayed independently of other slots and threads.
+    rng: fastrand::Rng,
+    history: SlotHistory,
+}
+
+impl SlotState {
+    /// Bookkeeping for `num_batch` fresh slots, each with its own RNG seeded from the calling thread's RNG.
+    fn new_shared(num_batch: usize) -> Arc<Mutex<Vec<Self>>> {
+        let slots = (0..num_batch).map(|_| Self::default()).collect();
+        Arc::new(Mutex::new(slots))
+    }
+}
+
+/// The part of [`SlotState`] that follows the sequence; it is reset, loaded and copied together with the GPU state.
+#[derive(Debug, Clone)]
+struct SlotHistory {
+    /// Bytes of generated text still waiting for the rest of their character.
+    text: Utf8Buffer,
+    /// Decayed count of each sampled token, used by [`Penalty`].
+    occurrence: HashMap<u32, f32>,
+    /// Running target surprise of mirostat.
+    mu: Option<f32>,
+    /// How far the sampled tokens have matched the grammar; `None` until the first token.
+    matched: Option<GrammarState>,
+    /// Tokens run since the state was cleared; `None` if the state was set from outside, a run was interrupted,
+    /// or the slot ran while the prefix cache was off, which keeps the history from growing when nothing reads it.
+    sequence: Option<Sequence>,
+}
+
+impl Default for SlotHistory {
+    fn default() -> Self {
+        Self {
+            text: Default::default(),
+            occurrence: Default::default(),
+            mu: None,
+            matched: None,
+            sequence: Some(Sequence::default()),
+        }
+    }
 }
 
 fn make_hooks_extended_v6<F: Float>(info: &ModelInfo) -> Result<v6::HookMap<F>> {
@@ -83,11 +187,15 @@ struct Prefab {
     info: ModelInfo,
 }
 
-async fn create_context(info: &ModelInfo) -> Result<Context> {
+async fn create_context(info: &ModelInfo, options: &RuntimeOptions) -> Result<Context> {
     let instance = wgpu::Instance::default();
-    let adapter = instance
-        .adapter(wgpu::PowerPreference::HighPerformance)
-        .await?;
+    let adapter = match options.adapter_index {
+        Some(index) => match instance.enumerate_adapters(wgpu::Backends::all()).into_iter().nth(index) {
+            Some(adapter) => adapter,
+            None => bail!(FfiError::InvalidArgument("adapter index out of range")),
+        },
+        None => instance.adapter(options.power_preference).await?,
+    };
     let context = ContextBuilder::new(adapter)
         .auto_limits(info)
         .build()
@@ -95,734 +203,3475 @@ async fn create_context(info: &ModelInfo) -> Result<Context> {
     Ok(context)
 }
 
-// async fn load_tokenizer(path: impl AsRef<Path>) -> Result<Tokenizer> {
-//     let file = File::open(path).await?;
-//     let mut reader = BufReader::new(file);
-//     let mut contents = String::new();
-//     reader.read_to_string(&mut contents).await?;
-//     Ok(Tokenizer::new(&contents)?)
-// }
+/// A model of any version, built from safetensors or deserialized from a prefab.
+enum AnyModel {
+    V4(v4::Model),
+    V5(v5::Model),
+    V6(v6::Model),
+    V7(v7::Model),
+}
+
+impl AnyModel {
+    /// The quantization each layer's weights are stored with.
+    fn layer_quant(&self) -> Vec<QuantKind> {
+        match self {
+            AnyModel::V4(model) => model.tensor.layers.iter().map(|layer| matrix_quant(&layer.ffn.w_k)).collect(),
+            AnyModel::V5(model) => model.tensor.layers.iter().map(|layer| matrix_quant(&layer.ffn.w_k)).collect(),
+            AnyModel::V6(model) => model.tensor.layers.iter().map(|layer| matrix_quant(&layer.ffn.w_k)).collect(),
+            AnyModel::V7(model) => model.tensor.layers.iter().map(|layer| matrix_quant(&layer.ffn.w_k)).collect(),
+        }
+    }
+
+    /// Write the model in the cbor layout read by [`build_prefab`]; its leading `info` field doubles as the prefab header.
+    fn write_prefab(&self, writer: impl std::io::Write) -> Result<()> {
+        struct CborWriter<W>(W);
+
+        impl<W: std::io::Write> cbor4ii::core::enc::Write for CborWriter<W> {
+            type Error = std::io::Error;
+
+            fn push(&mut self, input: &[u8]) -> Result<(), Self::Error> {
+                self.0.write_all(input)
+            }
+        }
+
+        let mut serializer = cbor4ii::serde::Serializer::new(CborWriter(writer));
+        match self {
+            AnyModel::V4(model) => model.serialize(&mut serializer)?,
+            AnyModel::V5(model) => model.serialize(&mut serializer)?,
+            AnyModel::V6(model) => model.serialize(&mut serializer)?,
+            AnyModel::V7(model) => model.serialize(&mut serializer)?,
+        }
+        serializer.into_inner().0.flush()?;
+        Ok(())
+    }
+}
+
+/// The runtime driving a bundle, together with the bundle's state.
+type Started = (TokioRuntime<Rnn>, Arc<dyn State + Sync + Send + 'static>);
+
+async fn start<B, J>(bundle: B) -> Started
+where
+    B: Bundle + Dispatcher<J, Info = RnnInfo> + Send + Sync + 'static,
+    J: Job<Input = RnnInput, Output = RnnOutput> + Send + 'static,
+{
+    let state = Arc::new(bundle.state());
+    let runtime = TokioRuntime::new(bundle).await;
+    (runtime, state)
+}
+
+fn bundle_v6<F: Float>(model: v6::Model, options: &RuntimeOptions) -> Result<v6::Bundle<F>> {
+    match options.extended {
+        true => {
+            let hooks = make_hooks_extended_v6(&model.info)?;
+            Ok(v6::Bundle::new_with_hooks(model, options.num_batch, hooks))
+        }
+        false => Ok(v6::Bundle::new(model, options.num_batch)),
+    }
+}
+
+fn bundle_v7<F: Float>(model: v7::Model, options: &RuntimeOptions) -> Result<v7::Bundle<F>> {
+    match options.extended {
+        true => {
+            let hooks = make_hooks_extended_v7(&model.info)?;
+            Ok(v7::Bundle::new_with_hooks(model, options.num_batch, hooks))
+        }
+        false => Ok(v7::Bundle::new(model, options.num_batch)),
+    }
+}
+
+/// Put the model into a bundle of the requested precision and start running it.
+/// Also returns the fingerprint of the model.
+async fn start_model(model: AnyModel, options: &RuntimeOptions) -> Result<(u64, Started)> {
+    let num_batch = options.num_batch;
+    let started = match model {
+        AnyModel::V4(model) => {
+            let fingerprint = state::fingerprint(&model.info, &model.tensor.embed.w);
+            match options.fp16 {
+                true => (fingerprint, start(v4::Bundle::<f16>::new(model, num_batch)).await),
+                false => (fingerprint, start(v4::Bundle::<f32>::new(model, num_batch)).await),
+            }
+        }
+        AnyModel::V5(model) => {
+            let fingerprint = state::fingerprint(&model.info, &model.tensor.embed.w);
+            match options.fp16 {
+                true => (fingerprint, start(v5::Bundle::<f16>::new(model, num_batch)).await),
+                false => (fingerprint, start(v5::Bundle::<f32>::new(model, num_batch)).await),
+            }
+        }
+        AnyModel::V6(model) => {
+            let fingerprint = state::fingerprint(&model.info, &model.tensor.embed.w);
+            match options.fp16 {
+                true => (fingerprint, start(bundle_v6::<f16>(model, options)?).await),
+                false => (fingerprint, start(bundle_v6::<f32>(model, options)?).await),
+            }
+        }
+        AnyModel::V7(model) => {
+            let fingerprint = state::fingerprint(&model.info, &model.tensor.embed.w);
+            match options.fp16 {
+                true => (fingerprint, start(bundle_v7::<f16>(model, options)?).await),
+                false => (fingerprint, start(bundle_v7::<f32>(model, options)?).await),
+            }
+        }
+    };
+    Ok(started)
+}
+
+/// Fail if the options ask for something the model cannot provide.
+fn check_options(info: &ModelInfo, options: &RuntimeOptions) -> Result<()> {
+    if options.extended && matches!(info.version, ModelVersion::V4 | ModelVersion::V5) {
+        bail!(FfiError::InvalidArgument("extended hooks are only available for v6 and v7 models"));
+    }
+    Ok(())
+}
+
+/// Build a model from safetensors bytes, quantizing and rescaling it as the options say.
+async fn build_safetensors(data: &[u8], options: &RuntimeOptions) -> Result<(ModelInfo, Context, AnyModel)> {
+    let model = SafeTensors::deserialize(data)?;
+    let info = Loader::info(&model)?;
+    log::info!("{:#?}", info);
+    check_options(&info, options)?;
+
+    let context = create_context(&info, options).await?;
+    log::info!("{:#?}", context.adapter.get_info());
+
+    let quant = options.quant.resolve(info.num_layer)?;
+    let builder = ModelBuilder::new(&context, model).quant(quant);
+    let builder = match options.rescale {
+        Some(rescale) => builder.rescale(rescale),
+        None => builder,
+    };
+    let model = match info.version {
+        ModelVersion::V4 => AnyModel::V4(builder.build_v4().await?),
+        ModelVersion::V5 => AnyModel::V5(builder.build_v5().await?),
+        ModelVersion::V6 => AnyModel::V6(builder.build_v6().await?),
+        ModelVersion::V7 => AnyModel::V7(builder.build_v7().await?),
+    };
+    Ok((info, context, model))
+}
+
+/// Deserialize a model from prefab bytes.
+async fn build_prefab(data: &[u8], options: &RuntimeOptions) -> Result<(ModelInfo, Context, AnyModel)> {
+    if !options.quant.is_empty() {
+        bail!(FfiError::InvalidArgument("a prefab is quantized when exported and takes no quant map"));
+    }
+    if options.rescale.is_some() {
+        bail!(FfiError::InvalidArgument("a prefab is rescaled when exported and takes no `rescale`"));
+    }
+
+    let Prefab { info } =
+        cbor4ii::serde::from_slice::<Prefab>(data).map_err(|err| FfiError::InvalidPrefab(err.to_string()))?;
+    log::info!("{:#?}", info);
+    check_options(&info, options)?;
+
+    let context = create_context(&info, options).await?;
+    log::info!("{:#?}", context.adapter.get_info());
+
+    let reader = cbor4ii::core::utils::SliceReader::new(data);
+    let mut deserializer = cbor4ii::serde::Deserializer::new(reader);
+    let invalid = |err: cbor4ii::serde::DecodeError<_>| FfiError::InvalidPrefab(err.to_string());
+    let model = match info.version {
+        ModelVersion::V4 => {
+            let seed: Seed<_, v4::Model> = Seed::new(&context);
+            AnyModel::V4(seed.deserialize(&mut deserializer).map_err(invalid)?)
+        }
+        ModelVersion::V5 => {
+            let seed: Seed<_, v5::Model> = Seed::new(&context);
+            AnyModel::V5(seed.deserialize(&mut deserializer).map_err(invalid)?)
+        }
+        ModelVersion::V6 => {
+            let seed: Seed<_, v6::Model> = Seed::new(&context);
+            AnyModel::V6(seed.deserialize(&mut deserializer).map_err(invalid)?)
+        }
+        ModelVersion::V7 => {
+            let seed: Seed<_, v7::Model> = Seed::new(&context);
+            AnyModel::V7(seed.deserialize(&mut deserializer).map_err(invalid)?)
+        }
+    };
+    Ok((info, context, model))
+}
+
+/// Load a model file with the given options.
+fn load_runtime(path: impl AsRef<Path>, options: RuntimeOptions) -> Result<WktvRuntime> {
+    let file = std::fs::File::open(path)?;
+    let data = unsafe { Mmap::map(&file)? };
+    load_runtime_from_memory(&data, options)
+}
+
+/// Build a model from a safetensors file with the given quantization and rescaling, and write it to `dst` as a prefab.
+fn write_prefab_file(src: impl AsRef<Path>, dst: impl AsRef<Path>, options: RuntimeOptions) -> Result<()> {
+    if options.format != ModelFormat::SafeTensors {
+        bail!(FfiError::InvalidArgument("a prefab can only be exported from safetensors"));
+    }
+    let file = std::fs::File::open(src)?;
+    let data = unsafe { Mmap::map(&file)? };
+
+    let tokio = tokio::runtime::Runtime::new()?;
+    let (_, _, model) = tokio.block_on(build_safetensors(&data, &options))?;
+
+    // Write next to `dst` and rename into place, so a failed export never leaves a truncated prefab behind.
+    let dst = dst.as_ref();
+    let Some(name) = dst.file_name() else {
+        bail!(FfiError::InvalidArgument("`dst` must name a file"));
+    };
+    let mut temp = name.to_os_string();
+    temp.push(".tmp");
+    let temp = dst.with_file_name(temp);
+    let written = (|| {
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(&temp)?);
+        model.write_prefab(&mut writer)?;
+        writer.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+        std::fs::rename(&temp, dst)?;
+        anyhow::Ok(())
+    })();
+    if written.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    written
+}
+
+/// Load a model from the bytes of a model file with the given options.
+///
+/// The weights are uploaded to the GPU, so `data` is only borrowed for the duration of the call.
+fn load_runtime_from_memory(data: &[u8], options: RuntimeOptions) -> Result<WktvRuntime> {
+    if options.num_batch == 0 {
+        bail!(FfiError::InvalidArgument("number of batch slots must be positive"));
+    }
 
-fn load_runtime(
-    model: impl AsRef<Path>,
-    quant: usize,
-    quant_nf4: usize,
-    quant_sf4: usize,
-    rescale: Option<usize>,
-    extended: bool,
-    fp16: bool,
-) -> Result<WktvRuntime> {
     let tokio = Arc::new(tokio::runtime::Runtime::new()?);
-    let _tokio = tokio.clone();
+    let (info, context, quant, fingerprint, (runtime, state)) = tokio.block_on(async {
+        let (info, context, model) = match options.format {
+            ModelFormat::SafeTensors => build_safetensors(data, &options).await?,
+            ModelFormat::Prefab => build_prefab(data, &options).await?,
+        };
+        let quant = model.layer_quant();
+        let (fingerprint, started) = start_model(model, &options).await?;
+        anyhow::Ok((info, context, quant, fingerprint, started))
+    })?;
+
+    Ok(WktvRuntime {
+        runtime,
+        info,
+        state,
+        context,
+        tokio,
+        fingerprint,
+        slots: SlotState::new_shared(options.num_batch),
+        calls: Arc::new(Calls::new(options.num_batch)),
+        cache: Default::default(),
+        initial: Default::default(),
+        token_chunk_size: options.token_chunk_size,
+        format: options.format,
+        fp16: options.fp16,
+        quant: quant.into(),
+    })
+}
 
-    _tokio.block_on(async move {
-        let file = File::open(model).await?;
-        let data = unsafe { Mmap::map(&file)? };
+impl WktvRuntime {
+    /// Number of batch slots of this runtime.
+    fn num_batch(&self) -> usize {
+        self.state.num_batch()
+    }
 
-        let model = SafeTensors::deserialize(&data)?;
-        let info = Loader::info(&model)?;
-        log::info!("{:#?}", info);
+    /// Run one input per batch slot through the model in the same dispatches, and collect the output of each slot.
+    ///
+    /// Slots with no tokens are left untouched and yield an empty output.
+    async fn run_batch(&self, batches: Vec<RnnInputBatch>) -> Result<Vec<Vec<f32>>> {
+        let options = batches.iter().map(|batch| batch.option).collect_vec();
+        let mut outputs = vec![vec![]; batches.len()];
+        self.run_batch_with(batches, |slot, chunk| match options[slot] {
+            RnnOption::Last => outputs[slot] = chunk.to_vec(),
+            RnnOption::Full => outputs[slot].extend_from_slice(chunk),
+        })
+        .await?;
+        Ok(outputs)
+    }
 
-        let context = create_context(&info).await?;
-        log::info!("{:#?}", context.adapter.get_info());
+    /// Run one input per batch slot through the model in the same dispatches,
+    /// handing the non-empty output of each slot for each chunk to `on_output` as it arrives.
+    ///
+    /// A cancellation of the call is honored before each chunk, so the state always ends on a chunk boundary.
+    /// With the prefix cache enabled, cached prefixes are restored first and snapshots are taken after each chunk.
+    async fn run_batch_with(
+        &self,
+        batches: Vec<RnnInputBatch>,
+        mut on_output: impl FnMut(usize, &[f32]),
+    ) -> Result<()> {
+        if batches.len() != self.num_batch() {
+            bail!(FfiError::BatchMismatch {
+                expected: self.num_batch(),
+                actual: batches.len(),
+            });
+        }
+        if batches.iter().all(|batch| batch.tokens.is_empty()) {
+            bail!(FfiError::EmptyInput);
+        }
 
-        let quant = (0..quant)
-            .map(|layer| (layer, Quant::Int8))
-            .chain((0..quant_nf4).map(|layer| (layer, Quant::NF4)))
-            .chain((0..quant_sf4).map(|layer| (layer, Quant::SF4)))
-            .collect();
+        let involved = (0..batches.len())
+            .filter(|&slot| !batches[slot].tokens.is_empty())
+            .collect_vec();
+        let _call = self.calls.begin(involved.iter().copied());
 
-        let builder = ModelBuilder::new(&context, model).quant(quant);
-        let builder = match rescale {
-            Some(rescale) => builder.rescale(rescale),
-            None => builder,
-        };
-        let runtime = match info.version {
-            ModelVersion::V4 => {
-                if fp16 {
-                    let model = builder.build_v4().await?;
-                    let bundle = v4::Bundle::<f16>::new(model, 1);
-                    let state = Arc::new(bundle.state());
-                    let runtime = TokioRuntime::new(bundle).await;
-                    WktvRuntime {
-                        runtime,
-                        info,
-                        state,
-                        context,
-                        tokio,
-                    }
-                } else {
-                    let model = builder.build_v4().await?;
-                    let bundle = v4::Bundle::<f32>::new(model, 1);
-                    let state = Arc::new(bundle.state());
-                    let runtime = TokioRuntime::new(bundle).await;
-                    WktvRuntime {
-                        runtime,
-                        info,
-                        state,
-                        context,
-                        tokio,
-                    }
-                }
+        let mut batches = batches;
+        self.restore_prefixes(&mut batches, &mut on_output)?;
+        if batches.iter().all(|batch| batch.tokens.is_empty()) {
+            return Ok(());
+        }
+
+        // Embeddings have no token id, so a slot fed with any of them loses track of its sequence.
+        // Without the cache no sequence is tracked at all.
+        let tracked = self.cache.lock().unwrap().is_some();
+        let tokens = batches
+            .iter()
+            .map(|batch| {
+                let tokens = batch.tokens.iter().map(|token| match token {
+                    Token::Token(token) => Some(*token),
+                    Token::Embed(_) => None,
+                });
+                (!batch.tokens.is_empty()).then(|| match tracked {
+                    true => tokens.collect::<Option<Vec<_>>>(),
+                    false => None,
+                })
+            })
+            .collect_vec();
+        let options = batches.iter().map(|batch| batch.option).collect_vec();
+        let snapshot = match self.cache.lock().unwrap().as_ref() {
+            Some(cache) => tokens
+                .iter()
+                .map(|tokens| matches!(tokens, Some(Some(tokens)) if cache.wants(tokens.len())))
+                .collect_vec(),
+            None => vec![false; batches.len()],
+        };
+        // The sequence of each tracked slot as of the last chunk run, taken out of the slot to be extended in place.
+        let mut sequences = {
+            let mut slots = self.slots.lock().unwrap();
+            let sequences = slots.iter_mut().zip(&tokens).map(|(slot, tokens)| match tokens {
+                Some(Some(_)) => slot.history.sequence.take(),
+                _ => None,
+            });
+            sequences.collect_vec()
+        };
+        let mut done = vec![0; batches.len()];
+
+        let mut inference = Some(RnnInput::new(batches, self.token_chunk_size));
+        let result = loop {
+            if self.calls.is_cancelled(involved.iter().copied()) {
+                break Err(anyhow::Error::from(FfiError::Cancelled));
             }
-            ModelVersion::V5 => {
-                if fp16 {
-                    let model = builder.build_v5().await?;
-                    let bundle = v5::Bundle::<f16>::new(model, 1);
-                    let state = Arc::new(bundle.state());
-                    let runtime = TokioRuntime::new(bundle).await;
-                    WktvRuntime {
-                        runtime,
-                        info,
-                        state,
-                        context,
-                        tokio,
-                    }
-                } else {
-                    let model = builder.build_v5().await?;
-                    let bundle = v5::Bundle::<f32>::new(model, 1);
-                    let state = Arc::new(bundle.state());
-                    let runtime = TokioRuntime::new(bundle).await;
-                    WktvRuntime {
-                        runtime,
-                        info,
-                        state,
-                        context,
-                        tokio,
-                    }
+            let input = inference.take().unwrap();
+            let remaining = input.batches.iter().map(|batch| batch.tokens.len()).collect_vec();
+            let (input, output) = match self.runtime.infer(input).await {
+                Ok(result) => result,
+                Err(err) => break Err(err.into()),
+            };
+            for (slot, batch) in output.0.iter().enumerate() {
+                if !batch.0.is_empty() {
+                    on_output(slot, &batch.0);
                 }
             }
-            ModelVersion::V6 => {
-                if fp16 {
-                    let model = builder.build_v6().await?;
-                    let bundle = match extended {
-                        true => {
-                            let hooks = make_hooks_extended_v6(&info)?;
-                            v6::Bundle::<f16>::new_with_hooks(model, 1, hooks)
-                        }
-                        false => v6::Bundle::<f16>::new(model, 1),
+
+            let snapshots = async {
+                for (slot, batch) in input.batches.iter().enumerate() {
+                    let run = remaining[slot] - batch.tokens.len();
+                    let (Some(Some(tokens)), Some(sequence)) = (&tokens[slot], sequences[slot].as_mut()) else {
+                        continue;
                     };
-                    let state = Arc::new(bundle.state());
-                    let runtime = TokioRuntime::new(bundle).await;
-                    WktvRuntime {
-                        runtime,
-                        info,
-                        state,
-                        context,
-                        tokio,
+                    if run == 0 {
+                        continue;
                     }
-                } else {
-                    let model = builder.build_v6().await?;
-                    let bundle = match extended {
-                        true => {
-                            let hooks = make_hooks_extended_v6(&info)?;
-                            v6::Bundle::<f32>::new_with_hooks(model, 1, hooks)
+                    sequence.extend(&tokens[done[slot]..done[slot] + run]);
+                    done[slot] += run;
+                    if snapshot[slot] {
+                        let output: &[f32] = &output.0[slot].0;
+                        let num_vocab = self.info.num_vocab;
+                        let output = match options[slot] {
+                            _ if output.len() < num_vocab => None,
+                            RnnOption::Last => Some(output.to_vec()),
+                            RnnOption::Full => Some(output[output.len() - num_vocab..].to_vec()),
+                        };
+                        let state = self.state.back(slot).await?;
+                        if let Some(cache) = self.cache.lock().unwrap().as_mut() {
+                            cache.insert(sequence, state, output);
                         }
-                        false => v6::Bundle::<f32>::new(model, 1),
-                    };
-                    let state = Arc::new(bundle.state());
-                    let runtime = TokioRuntime::new(bundle).await;
-                    WktvRuntime {
-                        runtime,
-                        info,
-                        state,
-                        context,
-                        tokio,
                     }
                 }
+                anyhow::Ok(())
+            };
+            if let Err(err) = snapshots.await {
+                break Err(err);
             }
-            ModelVersion::V7 => {
-                if fp16 {
-                    let model = builder.build_v7().await?;
-                    let bundle = match extended {
-                        true => {
-                            let hooks = make_hooks_extended_v7(&info)?;
-                            v7::Bundle::<f16>::new_with_hooks(model, 1, hooks)
-                        }
-                        false => v7::Bundle::<f16>::new(model, 1),
-                    };
-                    let state = Arc::new(bundle.state());
-                    let runtime = TokioRuntime::new(bundle).await;
-                    WktvRuntime {
-                        runtime,
-                        info,
-                        state,
-                        context,
-                        tokio,
-                    }
-                } else {
-                    let model = builder.build_v7().await?;
-                    let bundle = match extended {
-                        true => {
-                            let hooks = make_hooks_extended_v7(&info)?;
-                            v7::Bundle::<f32>::new_with_hooks(model, 1, hooks)
-                        }
-                        false => v7::Bundle::<f32>::new(model, 1),
-                    };
-                    let state = Arc::new(bundle.state());
-                    let runtime = TokioRuntime::new(bundle).await;
-                    WktvRuntime {
-                        runtime,
-                        info,
-                        state,
-                        context,
-                        tokio,
-                    }
-                }
+
+            if input.batches.iter().all(|batch| batch.tokens.is_empty()) {
+                break Ok(());
             }
+            inference.replace(input);
         };
-        Ok(runtime)
-    })
-}
 
-fn load_runtime_prefab(model: impl AsRef<Path>, fp16: bool) -> Result<WktvRuntime> {
+        // A cancelled run stops on a chunk boundary, so its sequence is still known;
+        // any other failure leaves the state somewhere within the tokens.
+        let cancelled = matches!(&result, Err(err) if matches!(err.downcast_ref(), Some(FfiError::Cancelled)));
+        let mut slots = self.slots.lock().unwrap();
+        for ((slot, tokens), sequence) in slots.iter_mut().zip(&tokens).zip(sequences) {
+            match (&result, tokens) {
+                (_, None) => {}
+                (Ok(_), Some(Some(_))) => slot.history.sequence = sequence,
+                (Err(_), Some(Some(_))) if cancelled => slot.history.sequence = sequence,
+                _ => slot.history.sequence = None,
+            }
+        }
+        result
+    }
 
-    let tokio = Arc::new(tokio::runtime::Runtime::new()?);
-    let _tokio = tokio.clone();
+    /// Restore the longest cached continuation of each slot that only wants its last output, dropping the tokens it covers.
+    ///
+    /// A slot whose tokens are all covered gets the cached logits handed to `on_output` and is left with no tokens to run.
+    fn restore_prefixes(&self, batches: &mut [RnnInputBatch], on_output: &mut impl FnMut(usize, &[f32])) -> Result<()> {
+        let mut cache = self.cache.lock().unwrap();
+        let Some(cache) = cache.as_mut() else {
+            return Ok(());
+        };
+        let mut slots = self.slots.lock().unwrap();
+        for (slot, batch) in batches.iter_mut().enumerate() {
+            if batch.option != RnnOption::Last || batch.tokens.is_empty() {
+                continue;
+            }
+            let Some(sequence) = slots[slot].history.sequence.as_mut() else {
+                continue;
+            };
+            let tokens = batch.tokens.iter().map(|token| match token {
+                Token::Token(token) => Some(*token),
+                Token::Embed(_) => None,
+            });
+            let Some(tokens) = tokens.collect::<Option<Vec<_>>>() else {
+                continue;
+            };
+            let Some(hit) = cache.lookup(sequence, &tokens) else {
+                continue;
+            };
+            self.state.load(hit.state, slot)?;
+            sequence.extend(&tokens[..hit.len]);
+            batch.tokens.drain(..hit.len);
+            if let Some(output) = hit.output {
+                on_output(slot, &output);
+            }
+        }
+        Ok(())
+    }
 
-    _tokio.block_on(async move {
-        let file = File::open(model).await?;
-        let data = unsafe { Mmap::map(&file)? };
+    /// Run the tokens through the first batch slot.
+    async fn run(&self, tokens: &[u32], option: RnnOption) -> Result<Vec<f32>> {
+        if tokens.is_empty() {
+            bail!(FfiError::EmptyInput);
+        }
+        let mut batches = vec![RnnInputBatch::default(); self.num_batch()];
+        batches[0] = RnnInputBatch::new(tokens.to_vec(), option);
+        Ok(self.run_batch(batches).await?.swap_remove(0))
+    }
 
-        let Prefab { info } = cbor4ii::serde::from_slice::<Prefab>(&data)?;
-
-        let reader = cbor4ii::core::utils::SliceReader::new(&data);
-        let mut deserializer = cbor4ii::serde::Deserializer::new(reader);
-
-        log::info!("{:#?}", info);
-        let context = create_context(&info).await?;
-
-        let runtime = match info.version {
-            ModelVersion::V4 => {
-                if fp16 {
-                    let seed: Seed<_, v4::Model> = Seed::new(&context);
-                    let model = seed.deserialize(&mut deserializer)?;
-                    let bundle = v4::Bundle::<f16>::new(model, 1);
-                    let state = Arc::new(bundle.state());
-                    let runtime = TokioRuntime::new(bundle).await;
-
-                    WktvRuntime {
-                        runtime,
-                        info,
-                        state,
-                        context,
-                        tokio,
-                    }
-                } else {
-                    let seed: Seed<_, v4::Model> = Seed::new(&context);
-                    let model = seed.deserialize(&mut deserializer)?;
-                    let bundle = v4::Bundle::<f32>::new(model, 1);
-                    let state = Arc::new(bundle.state());
-                    let runtime = TokioRuntime::new(bundle).await;
-
-                    WktvRuntime {
-                        runtime,
-                        info,
-                        state,
-                        context,
-                        tokio,
-                    }
-                }
+    /// Pick the next token of a slot from the logits of its last position, along with its log-probability.
+    ///
+    /// The slot's repetition penalties and logit bias are applied first, then the `bias` of this call,
+    /// then tokens that break its grammar are masked out. A pick with every token banned fails with `InvalidArgument`.
+    /// The picked token is counted for the next pick and advances the grammar.
+    /// If the slot has a [`SamplerConfig`], it is used in place of `sampler`.
+    /// The `top_n` most likely tokens of the final distribution are reported along with the pick.
+    async fn sample(
+        &self,
+        slot: usize,
+        mut output: Vec<f32>,
+        sampler: &Sampler,
+        bias: &[LogitBias],
+        top_n: usize,
+    ) -> Result<Sampled> {
+        let (config, mut mu, mut rng, grammar) = {
+            let slots = self.slots.lock().unwrap();
+            let SlotState {
+                penalty,
+                sampler: config,
+                bias: slot_bias,
+                grammar,
+                rng,
+                history,
+            } = &slots[slot];
+            penalty.apply(&mut output, &history.occurrence);
+            for (&token, &bias) in slot_bias {
+                output[token as usize] += bias;
             }
-            ModelVersion::V5 => {
-                if fp16 {
-                    let seed: Seed<_, v5::Model> = Seed::new(&context);
-                    let model = seed.deserialize(&mut deserializer)?;
-                    let bundle = v5::Bundle::<f16>::new(model, 1);
-                    let state = Arc::new(bundle.state());
-                    let runtime = TokioRuntime::new(bundle).await;
-
-                    WktvRuntime {
-                        runtime,
-                        info,
-                        state,
-                        context,
-                        tokio,
-                    }
-                } else {
-                    let seed: Seed<_, v5::Model> = Seed::new(&context);
-                    let model = seed.deserialize(&mut deserializer)?;
-                    let bundle = v5::Bundle::<f32>::new(model, 1);
-                    let state = Arc::new(bundle.state());
-                    let runtime = TokioRuntime::new(bundle).await;
-
-                    WktvRuntime {
-                        runtime,
-                        info,
-                        state,
-                        context,
-                        tokio,
-                    }
-                }
+            for bias in bias {
+                output[bias.token as usize] += bias.bias;
             }
-            ModelVersion::V6 => {
-                if fp16 {
-                    let seed: Seed<_, v6::Model> = Seed::new(&context);
-                    let model = seed.deserialize(&mut deserializer)?;
-                    let bundle = v6::Bundle::<f16>::new(model, 1);
-                    let state = Arc::new(bundle.state());
-                    let runtime = TokioRuntime::new(bundle).await;
-
-                    WktvRuntime {
-                        runtime,
-                        info,
-                        state,
-                        context,
-                        tokio,
-                    }
-                } else {
-                    let seed: Seed<_, v6::Model> = Seed::new(&context);
-                    let model = seed.deserialize(&mut deserializer)?;
-                    let bundle = v6::Bundle::<f32>::new(model, 1);
-                    let state = Arc::new(bundle.state());
-                    let runtime = TokioRuntime::new(bundle).await;
-
-                    WktvRuntime {
-                        runtime,
-                        info,
-                        state,
-                        context,
-                        tokio,
-                    }
+            let grammar = grammar.clone().map(|(grammar, vocab)| {
+                let state = history.matched.clone().unwrap_or_else(|| grammar.start());
+                (grammar, vocab, state)
+            });
+            let config = config.unwrap_or_else(|| (*sampler).into());
+            (config, history.mu, rng.clone(), grammar)
+        };
+
+        if let Some((grammar, vocab, state)) = &grammar {
+            let allowed = grammar.allowed(state, vocab, output.len());
+            if !allowed.contains(&true) {
+                bail!(FfiError::GrammarDeadEnd);
+            }
+            for (logit, allowed) in output.iter_mut().zip(allowed) {
+                if !allowed {
+                    *logit = f32::NEG_INFINITY;
                 }
             }
-            ModelVersion::V7 => {
-                if fp16 {
-                    let seed: Seed<_, v7::Model> = Seed::new(&context);
-                    let model = seed.deserialize(&mut deserializer)?;
-                    let bundle = v7::Bundle::<f16>::new(model, 1);
-                    let state = Arc::new(bundle.state());
-                    let runtime = TokioRuntime::new(bundle).await;
-
-                    WktvRuntime {
-                        runtime,
-                        info,
-                        state,
-                        context,
-                        tokio,
-                    }
-                } else {
-                    let seed: Seed<_, v7::Model> = Seed::new(&context);
-                    let model = seed.deserialize(&mut deserializer)?;
-                    let bundle = v7::Bundle::<f32>::new(model, 1);
-                    let state = Arc::new(bundle.state());
-                    let runtime = TokioRuntime::new(bundle).await;
-
-                    WktvRuntime {
-                        runtime,
-                        info,
-                        state,
-                        context,
-                        tokio,
-                    }
+        }
+        // The softmax of all negative infinities is NaN, which would silently pick token 0.
+        if output.iter().all(|&logit| logit == f32::NEG_INFINITY) {
+            bail!(FfiError::InvalidArgument("every token is banned"));
+        }
+
+        let (token, logprobs) = if !config.is_greedy() {
+            let output = softmax_one(&self.context, TensorCpu::from_data_1d(output)).await?;
+            let token = config.sample(&output, &mut mu, &mut rng);
+            (token, output.iter().map(|x| x.ln()).collect_vec())
+        } else {
+            let (token, max) = output
+                .iter()
+                .copied()
+                .enumerate()
+                .max_by(|(_, x), (_, y)| x.total_cmp(y))
+                .unwrap_or_default();
+            let sum: f32 = output.iter().map(|x| (x - max).exp()).sum();
+            let norm = max + sum.ln();
+            (token as u32, output.iter().map(|x| x - norm).collect_vec())
+        };
+        let logprob = logprobs[token as usize];
+        let top = logprobs
+            .into_iter()
+            .enumerate()
+            .k_largest_by(top_n, |(_, x), (_, y)| x.total_cmp(y))
+            .map(|(token, logprob)| TokenProb {
+                token: token as u32,
+                logprob,
+            })
+            .collect();
+
+        let matched = match &grammar {
+            Some((grammar, vocab, state)) => Some(grammar.advance(state, token, vocab)?),
+            None => None,
+        };
+        let mut slots = self.slots.lock().unwrap();
+        let SlotState {
+            penalty,
+            rng: slot_rng,
+            history,
+            ..
+        } = &mut slots[slot];
+        penalty.update(&mut history.occurrence, token);
+        *slot_rng = rng;
+        history.mu = mu;
+        history.matched = matched;
+        Ok(Sampled { token, logprob, top })
+    }
+
+    /// Whether the grammar of a slot has been completed and admits no further tokens.
+    fn grammar_done(&self, slot: usize) -> bool {
+        let slots = self.slots.lock().unwrap();
+        let SlotState { grammar, history, .. } = &slots[slot];
+        match (grammar, &history.matched) {
+            (Some((grammar, _)), Some(state)) => grammar.is_done(state),
+            _ => false,
+        }
+    }
+
+    /// Predict the next token, applying `bias` on top of the slot's own logit bias.
+    fn infer(&self, tokens: &[u32], sampler: &Sampler, bias: &[LogitBias]) -> Result<u32> {
+        self.tokio.block_on(async move {
+            let output = self.run(tokens, RnnOption::Last).await?;
+            let Sampled { token, .. } = self.sample(0, output, sampler, bias, 0).await?;
+            Ok(token)
+        })
+    }
+
+    /// Like [`WktvRuntime::infer`], but also report the log-probability of the token and the `top_n` most likely tokens.
+    fn infer_logprobs(&self, tokens: &[u32], sampler: &Sampler, top_n: usize) -> Result<Sampled> {
+        self.tokio.block_on(async move {
+            let output = self.run(tokens, RnnOption::Last).await?;
+            self.sample(0, output, sampler, &[], top_n).await
+        })
+    }
+
+    /// Pass the decoded bytes of slot 0 through its UTF-8 buffer.
+    fn emit_text(&self, bytes: &[u8]) -> Vec<u8> {
+        let mut slots = self.slots.lock().unwrap();
+        slots[0].history.text.push(bytes)
+    }
+
+    /// Encode the text, predict the next token, and return it along with its bytes.
+    ///
+    /// Like [`WktvRuntime::infer`], the predicted token is not fed back into the state.
+    /// Since the text does not continue from that token, the UTF-8 buffer of slot 0 is flushed rather than left
+    /// holding bytes that would otherwise be prepended to the output of an unrelated call.
+    fn infer_text(
+        &self,
+        tokenizer: &WktvTokenizer,
+        text: &[u8],
+        sampler: &Sampler,
+        bias: &[LogitBias],
+    ) -> Result<(u32, Vec<u8>)> {
+        let tokens = tokenizer.encode(text)?;
+        let token = self.infer(&tokens, sampler, bias)?;
+        let bytes = tokenizer.decode(&[token])?;
+        let mut slots = self.slots.lock().unwrap();
+        Ok((token, slots[0].history.text.flush(&bytes)))
+    }
+
+    /// Prefill the tokens, then sample and feed one token at a time until a stop condition is met.
+    ///
+    /// `on_token` sees each token and its log-probability as soon as it is sampled, and ends the loop by returning `false`.
+    /// Every sampled token, including the one that triggered the stop, is fed back into the state.
+    /// The tokens sampled so far are returned even if generation fails or is cancelled;
+    /// in that case the last of them may not have been fed back yet.
+    async fn generate(
+        &self,
+        tokens: &[u32],
+        sampler: &Sampler,
+        bias: &[LogitBias],
+        stop: &StopCondition<'_>,
+        mut on_token: impl FnMut(u32, f32) -> bool,
+    ) -> (Vec<u32>, Result<FinishReason>) {
+        // Sampling between the runs is part of the call, so a cancellation arriving then is not lost.
+        let _call = self.calls.begin([0]);
+        let mut output = vec![];
+        let reason = async {
+            let mut input = tokens.to_vec();
+            let mut reason = None;
+            loop {
+                let logits = self.run(&input, RnnOption::Last).await?;
+                if let Some(reason) = reason {
+                    break Ok(reason);
+                }
+                if output.len() >= stop.max_tokens {
+                    break Ok(FinishReason::MaxTokens);
                 }
+                let Sampled { token, logprob, .. } = self.sample(0, logits, sampler, bias, 0).await?;
+                output.push(token);
+                input = vec![token];
+                let resume = on_token(token, logprob);
+                reason = stop
+                    .check(&output)
+                    .or((!resume).then_some(FinishReason::Callback))
+                    .or(self.grammar_done(0).then_some(FinishReason::Grammar));
             }
+        }
+        .await;
+        (output, reason)
+    }
+
+    fn generate_tokens(
+        &self,
+        tokens: &[u32],
+        sampler: &Sampler,
+        bias: &[LogitBias],
+        stop: &StopCondition<'_>,
+    ) -> (Vec<u32>, Result<FinishReason>) {
+        self.tokio.block_on(self.generate(tokens, sampler, bias, stop, |_, _| true))
+    }
+
+    fn generate_stream(
+        &self,
+        tokens: &[u32],
+        sampler: &Sampler,
+        bias: &[LogitBias],
+        stop: &StopCondition<'_>,
+        on_token: impl FnMut(u32, f32) -> bool,
+    ) -> Result<FinishReason> {
+        let (_, reason) = self.tokio.block_on(self.generate(tokens, sampler, bias, stop, on_token));
+        reason
+    }
+
+    /// Encode the text and sample up to `max_tokens` tokens, feeding every one of them back into the state.
+    ///
+    /// A character split by the last token is held back and emitted by the next text call on this runtime.
+    /// Like [`WktvRuntime::generate`], the text generated so far is returned along with any error.
+    fn generate_text(
+        &self,
+        tokenizer: &WktvTokenizer,
+        text: &[u8],
+        sampler: &Sampler,
+        bias: &[LogitBias],
+        max_tokens: usize,
+    ) -> Result<(Vec<u8>, Result<()>)> {
+        let tokens = tokenizer.encode(text)?;
+        let stop = StopCondition {
+            max_tokens,
+            ..Default::default()
         };
-        Ok(runtime)
-    })
+        let (output, reason) = self.generate_tokens(&tokens, sampler, bias, &stop);
+        Ok((self.emit_text(&tokenizer.decode(&output)?), reason.map(|_| ())))
+    }
+
+    fn infer_raw_last(&self, tokens: &[u32]) -> Result<Vec<f32>> {
+        self.tokio.block_on(self.run(tokens, RnnOption::Last))
+    }
+
+    fn infer_raw_full(&self, tokens: &[u32]) -> Result<Vec<f32>> {
+        self.tokio.block_on(self.run(tokens, RnnOption::Full))
+    }
+
+    /// Run the tokens through the first batch slot, writing the predictions of all tokens straight into `output`.
+    fn infer_raw_full_into(&self, tokens: &[u32], output: &mut [f32]) -> Result<()> {
+        if tokens.is_empty() {
+            bail!(FfiError::EmptyInput);
+        }
+        let mut batches = vec![RnnInputBatch::default(); self.num_batch()];
+        batches[0] = RnnInputBatch::new(tokens.to_vec(), RnnOption::Full);
+        let mut offset = 0;
+        let on_output = |_, chunk: &[f32]| {
+            output[offset..offset + chunk.len()].copy_from_slice(chunk);
+            offset += chunk.len();
+        };
+        self.tokio.block_on(self.run_batch_with(batches, on_output))
+    }
+
+    /// Run the tokens through the first batch slot, writing the prediction of the last token straight into `output`.
+    fn infer_raw_last_into(&self, tokens: &[u32], output: &mut [f32]) -> Result<()> {
+        if tokens.is_empty() {
+            bail!(FfiError::EmptyInput);
+        }
+        let mut batches = vec![RnnInputBatch::default(); self.num_batch()];
+        batches[0] = RnnInputBatch::new(tokens.to_vec(), RnnOption::Last);
+        let on_output = |_, chunk: &[f32]| output[..chunk.len()].copy_from_slice(chunk);
+        self.tokio.block_on(self.run_batch_with(batches, on_output))
+    }
+
+    /// The number of floats the raw infer functions output for `len` tokens.
+    fn output_size(&self, len: usize, option: RnnOption) -> Result<usize> {
+        match option {
+            RnnOption::Last => Ok(self.info.num_vocab),
+            RnnOption::Full => match len.checked_mul(self.info.num_vocab) {
+                Some(size) => Ok(size),
+                None => bail!(FfiError::InvalidArgument("output size overflows")),
+            },
+        }
+    }
+
+    fn infer_batch(&self, batches: Vec<RnnInputBatch>) -> Result<Vec<Vec<f32>>> {
+        self.tokio.block_on(self.run_batch(batches))
+    }
+
+    fn check_slot(&self, slot: usize) -> Result<()> {
+        if slot >= self.num_batch() {
+            bail!(FfiError::SlotOutOfRange {
+                slot,
+                num_batch: self.num_batch(),
+            });
+        }
+        Ok(())
+    }
+
+    fn clear_state(&self, slot: usize) -> Result<()> {
+        self.check_slot(slot)?;
+        let initial = self.initial.read().unwrap().clone();
+        let tensor = initial.unwrap_or_else(|| self.state.init());
+        self.state.load(tensor, slot)?;
+        self.slots.lock().unwrap()[slot].history = SlotHistory::default();
+        Ok(())
+    }
+
+    fn get_state(&self, slot: usize) -> Result<Vec<f32>> {
+        self.check_slot(slot)?;
+        let tensor = self.tokio.block_on(self.state.back(slot))?;
+        Ok(tensor.to_vec())
+    }
+
+    fn get_state_into(&self, slot: usize, output: &mut [f32]) -> Result<()> {
+        self.check_slot(slot)?;
+        let tensor = self.tokio.block_on(self.state.back(slot))?;
+        output[..tensor.len()].copy_from_slice(&tensor);
+        Ok(())
+    }
+
+    fn set_state(&self, slot: usize, state: &[f32]) -> Result<()> {
+        self.check_slot(slot)?;
+        let shape = self.state.init_shape();
+        if state.len() != shape.len() {
+            bail!(FfiError::StateSizeMismatch {
+                expected: shape.len(),
+                actual: state.len(),
+            });
+        }
+        let tensor: TensorCpu<f32> = self.context.tensor_from_data(shape, state.to_vec())?;
+        self.state.load(tensor, slot)?;
+        self.slots.lock().unwrap()[slot].history = SlotHistory {
+            sequence: None,
+            ..Default::default()
+        };
+        Ok(())
+    }
+
+    /// Replace the state slots are cleared to with the one in a state-tuning file, or with zeros if `path` is `None`,
+    /// then clear every slot.
+    fn load_initial_state(&self, path: Option<String>) -> Result<()> {
+        let initial = match path {
+            Some(path) => Some(self.tokio.block_on(self.read_initial_state(path))?),
+            None => None,
+        };
+        *self.initial.write().unwrap() = initial;
+        // Cached states were run from the old initial state.
+        if let Some(cache) = self.cache.lock().unwrap().as_mut() {
+            cache.clear();
+        }
+        (0..self.num_batch()).try_for_each(|slot| self.clear_state(slot))
+    }
+
+    async fn read_initial_state(&self, path: String) -> Result<TensorCpu<f32>> {
+        let file = File::open(path).await?;
+        let data = unsafe { Mmap::map(&file)? };
+        let model = SafeTensors::deserialize(&data)?;
+        let tensor = match self.info.version {
+            ModelVersion::V4 => bail!(FfiError::InvalidArgument("state tuning requires a v5 or later model")),
+            ModelVersion::V5 => v5::read_state(&self.context, &self.info, model).await?,
+            ModelVersion::V6 => v6::read_state(&self.context, &self.info, model).await?,
+            ModelVersion::V7 => v7::read_state(&self.context, &self.info, model).await?,
+        };
+        Ok(tensor)
+    }
+
+    /// Write the state of a slot to a safetensors file, along with the model's dimensions and fingerprint and the slot's RNG state.
+    fn save_state(&self, slot: usize, path: impl AsRef<Path>) -> Result<()> {
+        self.check_slot(slot)?;
+        let tensor = self.tokio.block_on(self.state.back(slot))?;
+        let shape = <[usize; 4]>::from(self.state.init_shape()).into_iter().rev().collect_vec();
+        let data = tensor.iter().flat_map(|x| x.to_le_bytes()).collect_vec();
+        let view = safetensors::tensor::TensorView::new(safetensors::Dtype::F32, shape, &data)?;
+        let header = StateHeader::new(&self.info, self.fingerprint, self.rng_state(slot)?);
+        safetensors::serialize_to_file([("state", view)], Some(header.to_metadata()), path.as_ref())?;
+        Ok(())
+    }
+
+    /// Load a state written by [`Self::save_state`] into a slot, refusing states of other models.
+    fn load_state(&self, slot: usize, path: impl AsRef<Path>) -> Result<()> {
+        self.check_slot(slot)?;
+        let data = std::fs::read(path)?;
+        let (_, metadata) = SafeTensors::read_metadata(&data)?;
+        let header = StateHeader::from_metadata(metadata.metadata().as_ref())?;
+        header.check(&StateHeader::new(&self.info, self.fingerprint, header.rng))?;
+
+        let file = SafeTensors::deserialize(&data)?;
+        let view = file.tensor("state")?;
+        let shape = <[usize; 4]>::from(self.state.init_shape()).into_iter().rev().collect_vec();
+        if view.dtype() != safetensors::Dtype::F32 || view.shape() != shape {
+            bail!(FfiError::IncompatibleState(format!(
+                "expected f32 tensor of shape {shape:?}, found {:?} tensor of shape {:?}",
+                view.dtype(),
+                view.shape()
+            )));
+        }
+        let state = view
+            .data()
+            .chunks_exact(4)
+            .map(|x| f32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+            .collect_vec();
+        self.set_state(slot, &state)?;
+        self.set_rng_state(slot, header.rng)
+    }
+
+    /// Copy the state of slot `src` into slot `dst` on the GPU.
+    /// Fork slot `src` into `dst`: the state, its history and the RNG, so both replay the same sampling.
+    fn copy_state(&self, src: usize, dst: usize) -> Result<()> {
+        self.check_slot(src)?;
+        self.check_slot(dst)?;
+        if src != dst {
+            let tensor = self.state.read(src)?;
+            self.state.write(tensor, dst)?;
+            let mut slots = self.slots.lock().unwrap();
+            slots[dst].history = slots[src].history.clone();
+            slots[dst].rng = slots[src].rng.clone();
+        }
+        Ok(())
+    }
+
+    fn set_penalty(&self, slot: usize, penalty: Penalty) -> Result<()> {
+        self.check_slot(slot)?;
+        self.slots.lock().unwrap()[slot].penalty = penalty;
+        Ok(())
+    }
+
+    fn set_sampler_config(&self, slot: usize, config: Option<SamplerConfig>) -> Result<()> {
+        self.check_slot(slot)?;
+        if let Some(config) = &config {
+            config.validate()?;
+        }
+        let mut slots = self.slots.lock().unwrap();
+        slots[slot].sampler = config;
+        slots[slot].history.mu = None;
+        Ok(())
+    }
+
+    /// Fail if a bias names a token outside the vocabulary, or would make the softmax NaN on its own.
+    fn check_bias(&self, bias: &[LogitBias]) -> Result<()> {
+        if bias.iter().any(|bias| bias.token as usize >= self.info.num_vocab) {
+            bail!(FfiError::InvalidArgument("biased token out of vocabulary"));
+        }
+        if bias.iter().any(|bias| bias.bias.is_nan() || bias.bias == f32::INFINITY) {
+            bail!(FfiError::InvalidArgument("a bias must be a number below positive infinity"));
+        }
+        Ok(())
+    }
+
+    fn set_logit_bias(&self, slot: usize, bias: &[LogitBias]) -> Result<()> {
+        self.check_slot(slot)?;
+        self.check_bias(bias)?;
+        let bias = bias.iter().map(|bias| (bias.token, bias.bias)).collect();
+        self.slots.lock().unwrap()[slot].bias = bias;
+        Ok(())
+    }
+
+    fn set_grammar(&self, slot: usize, grammar: Option<(Arc<Grammar>, Arc<Vocab>)>) -> Result<()> {
+        self.check_slot(slot)?;
+        let mut slots = self.slots.lock().unwrap();
+        slots[slot].grammar = grammar;
+        slots[slot].history.matched = None;
+        Ok(())
+    }
+
+    /// Read the RNG state of a slot, which can later be passed to [`WktvRuntime::set_rng_state`] to replay sampling.
+    fn rng_state(&self, slot: usize) -> Result<u64> {
+        self.check_slot(slot)?;
+        Ok(self.slots.lock().unwrap()[slot].rng.get_seed())
+    }
+
+    fn set_rng_state(&self, slot: usize, state: u64) -> Result<()> {
+        self.check_slot(slot)?;
+        self.slots.lock().unwrap()[slot].rng.seed(state);
+        Ok(())
+    }
+
+    /// Replace the prefix cache with an empty one, or disable it if `budget` is 0.
+    ///
+    /// Disabling it drops the sequences of all slots, which are only tracked for the cache.
+    fn set_prefix_cache(&self, budget: usize, min_tokens: usize) {
+        let cache = (budget > 0).then(|| PrefixCache::new(budget, min_tokens.max(1)));
+        let disabled = cache.is_none();
+        *self.cache.lock().unwrap() = cache;
+        if disabled {
+            let mut slots = self.slots.lock().unwrap();
+            slots.iter_mut().for_each(|slot| slot.history.sequence = None);
+        }
+    }
+
+    fn model_info(&self) -> ModelInfoOutput {
+        let info = &self.info;
+        ModelInfoOutput {
+            version: match info.version {
+                ModelVersion::V4 => 4,
+                ModelVersion::V5 => 5,
+                ModelVersion::V6 => 6,
+                ModelVersion::V7 => 7,
+            },
+            num_layer: info.num_layer,
+            num_hidden: info.num_hidden,
+            num_emb: info.num_emb,
+            num_vocab: info.num_vocab,
+            num_head: info.num_head,
+            format: self.format,
+            precision: match self.fp16 {
+                true => Precision::F16,
+                false => Precision::F32,
+            },
+            num_int8: self.quant.iter().filter(|&&quant| quant == QuantKind::Int8).count(),
+            num_nf4: self.quant.iter().filter(|&&quant| quant == QuantKind::NF4).count(),
+            num_sf4: self.quant.iter().filter(|&&quant| quant == QuantKind::SF4).count(),
+        }
+    }
+}
+
+/// Get a copy of the global runtime.
+fn global_runtime() -> Result<WktvRuntime> {
+    let runtime = RUNTIME.read().unwrap().clone();
+    runtime.ok_or_else(|| FfiError::NotLoaded.into())
+}
+
+/// Run `f` on the global runtime, reporting [`WktvStatus::NotLoaded`] if there is none.
+fn with_global_runtime(f: impl FnOnce(&WktvRuntime) -> WktvStatus) -> WktvStatus {
+    match global_runtime() {
+        Ok(runtime) => f(&runtime),
+        Err(err) => report(Err(err)),
+    }
+}
+
+/// Borrow the runtime behind a handle.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is either null or a live handle returned by a `*_create` loader.
+unsafe fn runtime_from_handle<'a>(runtime: *const WktvRuntime) -> Result<&'a WktvRuntime> {
+    let runtime = unsafe { runtime.as_ref() };
+    runtime.ok_or_else(|| FfiError::NullHandle.into())
+}
+
+/// Build a token slice from a raw pointer, allowing null for an empty input.
+///
+/// # Safety
+///
+/// The caller must ensure that `tokens` is valid and `len` does not exceed the actual length of `tokens`.
+unsafe fn tokens_from_raw<'a>(tokens: *const u32, len: usize) -> &'a [u32] {
+    match tokens.is_null() {
+        true => &[],
+        false => unsafe { std::slice::from_raw_parts(tokens, len) },
+    }
+}
+
+/// Read a logit bias list, treating null as empty.
+///
+/// # Safety
+///
+/// The caller must ensure that `bias` is either null or valid for `len` elements.
+unsafe fn bias_from_raw<'a>(bias: *const LogitBias, len: usize) -> &'a [LogitBias] {
+    match bias.is_null() {
+        true => &[],
+        false => unsafe { std::slice::from_raw_parts(bias, len) },
+    }
+}
+
+/// Read a path from a C string.
+///
+/// # Safety
+///
+/// The caller must ensure that `path` is either null or a valid C string.
+unsafe fn path_from_raw(path: *const c_char) -> Result<String> {
+    if path.is_null() {
+        bail!(FfiError::NullPointer("path"));
+    }
+    Ok(unsafe { CStr::from_ptr(path).to_string_lossy().to_string() })
+}
+
+/// Borrow a quant map passed in from C.
+///
+/// # Safety
+///
+/// The caller must ensure that `layers` is either null or valid for `len` elements.
+unsafe fn layers_from_raw<'a>(layers: *const LayerQuant, len: usize) -> Result<&'a [LayerQuant]> {
+    match (layers.is_null(), len) {
+        (_, 0) => Ok(&[]),
+        (true, _) => bail!(FfiError::NullPointer("layers")),
+        (false, _) => Ok(unsafe { std::slice::from_raw_parts(layers, len) }),
+    }
+}
+
+/// Borrow a caller-provided output buffer, checking that it holds at least `required` values.
+///
+/// # Safety
+///
+/// The caller must ensure that `data` is either null or valid for `len` writes.
+unsafe fn floats_from_raw<'a>(data: *mut f32, len: usize, required: usize) -> Result<&'a mut [f32]> {
+    if data.is_null() {
+        bail!(FfiError::NullPointer("output"));
+    }
+    if len < required {
+        bail!(FfiError::BufferTooSmall { required, actual: len });
+    }
+    Ok(unsafe { std::slice::from_raw_parts_mut(data, len) })
+}
+
+/// Write a value through an output pointer.
+///
+/// # Safety
+///
+/// The caller must ensure that `output` is either null or valid for writes.
+unsafe fn write_output<T>(output: *mut T, value: T) -> Result<()> {
+    let Some(output) = (unsafe { output.as_mut() }) else {
+        bail!(FfiError::NullPointer("output"));
+    };
+    *output = value;
+    Ok(())
+}
+
+/// Move a loaded runtime into the global slot, replacing the previous one.
+fn replace_global(runtime: WktvRuntime) {
+    let mut rt = RUNTIME.write().unwrap();
+    rt.replace(runtime);
+}
+
+/// Box a loaded runtime into a handle and write it through `output`.
+///
+/// # Safety
+///
+/// The caller must ensure that `output` is either null or valid for writes.
+unsafe fn create_handle(output: *mut *mut WktvRuntime, runtime: WktvRuntime) -> Result<()> {
+    if output.is_null() {
+        bail!(FfiError::NullPointer("runtime"));
+    }
+    let runtime = Box::into_raw(Box::new(runtime));
+    unsafe { write_output(output, runtime) }
+}
+
+/// Cancels the call running on one slot of a runtime from another thread.
+///
+/// This is opaque to C; handles to it are created by `create_cancel_token` and freed by `release_cancel_token`.
+pub struct WktvCancelToken {
+    calls: Arc<Calls>,
+    slot: usize,
+}
+
+/// Create a cancellation token for the global runtime; see `create_cancel_token_handle`.
+///
+/// # Safety
+///
+/// The caller must ensure that `token` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn create_cancel_token(token: *mut *mut WktvCancelToken) -> WktvStatus {
+    with_global_runtime(|runtime| unsafe { create_cancel_token_handle(runtime, token) })
+}
+
+/// Create a cancellation token for the calls running on the first batch slot of a runtime handle.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle and `token` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn create_cancel_token_handle(
+    runtime: *const WktvRuntime,
+    token: *mut *mut WktvCancelToken,
+) -> WktvStatus {
+    unsafe { create_cancel_token_slot(runtime, 0, token) }
+}
+
+/// Create a cancellation token for the calls running on one batch slot of a runtime handle.
+///
+/// A batched call is cancelled through the token of any slot it runs tokens on.
+/// The token stays valid after the runtime is released, and must be freed with `release_cancel_token`.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle and `token` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn create_cancel_token_slot(
+    runtime: *const WktvRuntime,
+    slot: usize,
+    token: *mut *mut WktvCancelToken,
+) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { runtime_from_handle(runtime) }?;
+        runtime.check_slot(slot)?;
+        if token.is_null() {
+            bail!(FfiError::NullPointer("token"));
+        }
+        let calls = runtime.calls.clone();
+        let handle = Box::into_raw(Box::new(WktvCancelToken { calls, slot }));
+        unsafe { write_output(token, handle) }
+    })())
+}
+
+/// Request cancellation of the inference or generation running on the token's slot. Safe to call from any thread.
+///
+/// The running call stops before its next chunk of at most `token_chunk_size` tokens and returns `WktvStatus::Cancelled`.
+/// Only the call running at the time of the request is cancelled; if the slot is idle, the request has no effect.
+///
+/// # Safety
+///
+/// The caller must ensure that `token` is a live handle returned by `create_cancel_token`.
+#[no_mangle]
+pub unsafe extern "C" fn cancel(token: *const WktvCancelToken) -> WktvStatus {
+    report((|| {
+        let Some(token) = (unsafe { token.as_ref() }) else {
+            bail!(FfiError::NullHandle);
+        };
+        token.calls.cancel(token.slot);
+        Ok(())
+    })())
+}
+
+/// Release a cancellation token. The token must not be used afterwards.
+///
+/// # Safety
+///
+/// The caller must ensure that `token` is a handle returned by `create_cancel_token` and not yet released.
+#[no_mangle]
+pub unsafe extern "C" fn release_cancel_token(token: *mut WktvCancelToken) -> WktvStatus {
+    report((|| {
+        if token.is_null() {
+            bail!(FfiError::NullHandle);
+        }
+        let _ = unsafe { Box::from_raw(token) };
+        Ok(())
+    })())
+}
+
+/// Initialize logger and RNG. Call this once before everything.
+#[no_mangle]
+pub extern "C" fn init(seed: u64) {
+    let _ = simple_logger::SimpleLogger::new()
+        .with_level(log::LevelFilter::Warn)
+        .with_module_level("web_rwkv", log::LevelFilter::Info)
+        .with_module_level("web_rwkv_ffi", log::LevelFilter::Info)
+        .init();
+    fastrand::seed(seed);
+}
+
+/// Set the RNG seed.
+///
+/// Every slot of the global runtime, if loaded, is reseeded from it as well.
+#[no_mangle]
+pub extern "C" fn seed(seed: u64) {
+    fastrand::seed(seed);
+    if let Ok(runtime) = global_runtime() {
+        let mut slots = runtime.slots.lock().unwrap();
+        slots.iter_mut().for_each(|slot| slot.rng = fastrand::Rng::new());
+    }
+}
+
+/// Read the RNG state of slot 0 of the global runtime; see `get_rng_state_slot`.
+///
+/// # Safety
+///
+/// The caller must ensure that `output` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn get_rng_state(output: *mut u64) -> WktvStatus {
+    with_global_runtime(|runtime| unsafe { get_rng_state_slot(runtime, 0, output) })
+}
+
+/// Read the RNG state of slot 0 of a runtime handle.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle and `output` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn get_rng_state_handle(runtime: *const WktvRuntime, output: *mut u64) -> WktvStatus {
+    unsafe { get_rng_state_slot(runtime, 0, output) }
+}
+
+/// Read the RNG state of one batch slot.
+///
+/// Saving it together with `get_state_slot`, and restoring both later, replays sampling on the slot bit-exactly.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle and `output` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn get_rng_state_slot(runtime: *const WktvRuntime, slot: usize, output: *mut u64) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { runtime_from_handle(runtime) }?;
+        let state = runtime.rng_state(slot)?;
+        unsafe { write_output(output, state) }
+    })())
+}
+
+/// Set the RNG state of slot 0 of the global runtime, either a seed or a value read by `get_rng_state`.
+#[no_mangle]
+pub extern "C" fn set_rng_state(state: u64) -> WktvStatus {
+    with_global_runtime(|runtime| unsafe { set_rng_state_slot(runtime, 0, state) })
+}
+
+/// Set the RNG state of slot 0 of a runtime handle.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn set_rng_state_handle(runtime: *const WktvRuntime, state: u64) -> WktvStatus {
+    unsafe { set_rng_state_slot(runtime, 0, state) }
+}
+
+/// Set the RNG state of one batch slot, either to a seed or to a value read by `get_rng_state_slot`.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn set_rng_state_slot(runtime: *const WktvRuntime, slot: usize, state: u64) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { runtime_from_handle(runtime) }?;
+        runtime.set_rng_state(slot, state)
+    })())
+}
+
+/// Enable the prefix cache of the global runtime, see `set_prefix_cache_handle`.
+#[no_mangle]
+pub extern "C" fn set_prefix_cache(budget: usize, min_tokens: usize) -> WktvStatus {
+    with_global_runtime(|runtime| unsafe { set_prefix_cache_handle(runtime, budget, min_tokens) })
+}
+
+/// Enable the prefix cache of a runtime handle with a budget of `budget` bytes, dropping all cached states.
+///
+/// Every run of at least `min_tokens` tokens through a slot whose sequence since its last clear is known
+/// stores the state after each of its chunks. Later runs needing only the last output restore the longest cached
+/// continuation and only run the rest.
+/// The least recently used states are evicted to stay within the budget. A `budget` of 0 disables the cache.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn set_prefix_cache_handle(
+    runtime: *const WktvRuntime,
+    budget: usize,
+    min_tokens: usize,
+) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { runtime_from_handle(runtime) }?;
+        runtime.set_prefix_cache(budget, min_tokens);
+        Ok(())
+    })())
+}
+
+/// Load a state-tuning file into the global runtime, see `load_initial_state_handle`.
+///
+/// # Safety
+///
+/// The caller must ensure that `path` is either null or valid.
+#[no_mangle]
+pub unsafe extern "C" fn load_initial_state(path: *const c_char) -> WktvStatus {
+    with_global_runtime(|runtime| unsafe { load_initial_state_handle(runtime, path) })
+}
+
+/// Load the tuned initial state from a state-tuning safetensors file (v5, v6 and v7 models only),
+/// and clear every slot of a runtime handle to it. `clear_state` resets to it from then on.
+/// Pass null to go back to the all-zero initial state.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle and `path` is either null or valid.
+#[no_mangle]
+pub unsafe extern "C" fn load_initial_state_handle(runtime: *const WktvRuntime, path: *const c_char) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { runtime_from_handle(runtime) }?;
+        let path = match path.is_null() {
+            true => None,
+            false => Some(unsafe { path_from_raw(path) }?),
+        };
+        runtime.load_initial_state(path)
+    })())
+}
+
+/// Save the state of the global runtime to a file, see `save_state_slot`.
+///
+/// # Safety
+///
+/// The caller must ensure that `path` is valid.
+#[no_mangle]
+pub unsafe extern "C" fn save_state(path: *const c_char) -> WktvStatus {
+    with_global_runtime(|runtime| unsafe { save_state_slot(runtime, 0, path) })
+}
+
+/// Save the state of slot 0 of a runtime handle to a file.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle and `path` is valid.
+#[no_mangle]
+pub unsafe extern "C" fn save_state_handle(runtime: *const WktvRuntime, path: *const c_char) -> WktvStatus {
+    unsafe { save_state_slot(runtime, 0, path) }
+}
+
+/// Save the state of one batch slot to a safetensors file.
+///
+/// The file records the model's version, dimensions and a fingerprint of its weights, and the slot's RNG state.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle and `path` is valid.
+#[no_mangle]
+pub unsafe extern "C" fn save_state_slot(runtime: *const WktvRuntime, slot: usize, path: *const c_char) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { runtime_from_handle(runtime) }?;
+        let path = unsafe { path_from_raw(path) }?;
+        runtime.save_state(slot, path)
+    })())
+}
+
+/// Load a state file into the global runtime, see `load_state_slot`.
+///
+/// # Safety
+///
+/// The caller must ensure that `path` is valid.
+#[no_mangle]
+pub unsafe extern "C" fn load_state(path: *const c_char) -> WktvStatus {
+    with_global_runtime(|runtime| unsafe { load_state_slot(runtime, 0, path) })
+}
+
+/// Load a state file into slot 0 of a runtime handle.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle and `path` is valid.
+#[no_mangle]
+pub unsafe extern "C" fn load_state_handle(runtime: *const WktvRuntime, path: *const c_char) -> WktvStatus {
+    unsafe { load_state_slot(runtime, 0, path) }
+}
+
+/// Load a file written by `save_state_slot` into one batch slot, restoring its RNG state as well.
+///
+/// Fails with `WktvStatus::ShapeMismatch` and leaves the slot untouched if the file was saved from a different model.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle and `path` is valid.
+#[no_mangle]
+pub unsafe extern "C" fn load_state_slot(runtime: *const WktvRuntime, slot: usize, path: *const c_char) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { runtime_from_handle(runtime) }?;
+        let path = unsafe { path_from_raw(path) }?;
+        runtime.load_state(slot, path)
+    })())
+}
+
+/// Load the global runtime as described by `options`.
+///
+/// # Safety
+///
+/// The caller must ensure that `options` points to a valid `LoadOptions`.
+#[no_mangle]
+pub unsafe extern "C" fn load_with_options(options: *const LoadOptions) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { load_runtime_from_raw(options) }?;
+        replace_global(runtime);
+        Ok(())
+    })())
+}
+
+/// Load a runtime as described by `options`, and write its handle into `runtime`.
+///
+/// Every other loader is a shorthand for this one with some options fixed.
+///
+/// # Safety
+///
+/// The caller must ensure that `options` points to a valid `LoadOptions` and `runtime` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn load_with_options_create(
+    options: *const LoadOptions,
+    runtime: *mut *mut WktvRuntime,
+) -> WktvStatus {
+    report((|| {
+        let loaded = unsafe { load_runtime_from_raw(options) }?;
+        unsafe { create_handle(runtime, loaded) }
+    })())
+}
+
+/// Load the global runtime from the `len` bytes of a safetensors or prefab file at `data`, as described by `options`.
+///
+/// The `path` of `options` is ignored and may be null. The bytes are only read during the call,
+/// so they may be freed as soon as it returns.
+///
+/// # Safety
+///
+/// The caller must ensure that `data` is valid for `len` bytes and `options` points to a valid `LoadOptions`.
+#[no_mangle]
+pub unsafe extern "C" fn load_from_memory(data: *const u8, len: usize, options: *const LoadOptions) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { load_runtime_from_raw_memory(data, len, options) }?;
+        replace_global(runtime);
+        Ok(())
+    })())
+}
+
+/// Load a runtime from the `len` bytes of a safetensors or prefab file at `data`, and write its handle into `runtime`.
+///
+/// # Safety
+///
+/// The caller must ensure that `data` is valid for `len` bytes, `options` points to a valid `LoadOptions`
+/// and `runtime` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn load_from_memory_create(
+    data: *const u8,
+    len: usize,
+    options: *const LoadOptions,
+    runtime: *mut *mut WktvRuntime,
+) -> WktvStatus {
+    report((|| {
+        let loaded = unsafe { load_runtime_from_raw_memory(data, len, options) }?;
+        unsafe { create_handle(runtime, loaded) }
+    })())
+}
+
+/// Build a model from the safetensors file at `src` and write it to `dst` as a prefab for `load_prefab`.
+///
+/// The quant map, `rescale` and GPU choice of `options` are applied while building; its `path` is ignored,
+/// and `format` must be `ModelFormat::SafeTensors`.
+/// The prefab is written to a temporary file beside `dst` and only renamed over it once complete.
+///
+/// # Safety
+///
+/// The caller must ensure that `src` and `dst` are valid and `options` points to a valid `LoadOptions`.
+#[no_mangle]
+pub unsafe extern "C" fn export_prefab(
+    src: *const c_char,
+    dst: *const c_char,
+    options: *const LoadOptions,
+) -> WktvStatus {
+    report((|| {
+        let src = unsafe { path_from_raw(src) }?;
+        let dst = unsafe { path_from_raw(dst) }?;
+        let (_, options) = unsafe { options_from_raw(options) }?;
+        write_prefab_file(src, dst, options)
+    })())
+}
+
+/// # Safety
+///
+/// The caller must ensure that `options` is either null or points to a valid `LoadOptions`.
+unsafe fn options_from_raw(options: *const LoadOptions) -> Result<(Option<String>, RuntimeOptions)> {
+    let Some(options) = (unsafe { options.as_ref() }) else {
+        bail!(FfiError::NullPointer("options"));
+    };
+    unsafe { RuntimeOptions::from_raw(options) }
+}
+
+/// # Safety
+///
+/// The caller must ensure that `options` is either null or points to a valid `LoadOptions`.
+unsafe fn load_runtime_from_raw(options: *const LoadOptions) -> Result<WktvRuntime> {
+    let (path, options) = unsafe { options_from_raw(options) }?;
+    let Some(path) = path else {
+        bail!(FfiError::NullPointer("path"));
+    };
+    load_runtime(path, options)
+}
+
+/// # Safety
+///
+/// The caller must ensure that `data` is either null or valid for `len` bytes,
+/// and `options` is either null or points to a valid `LoadOptions`.
+unsafe fn load_runtime_from_raw_memory(data: *const u8, len: usize, options: *const LoadOptions) -> Result<WktvRuntime> {
+    if data.is_null() {
+        bail!(FfiError::NullPointer("data"));
+    }
+    if len == 0 {
+        bail!(FfiError::InvalidArgument("model data cannot be empty"));
+    }
+    let (_, options) = unsafe { options_from_raw(options) }?;
+    let data = unsafe { std::slice::from_raw_parts(data, len) };
+    load_runtime_from_memory(data, options)
+}
+
+/// Load a runtime.
+///
+/// # Safety
+///
+/// The caller must ensure that `model` is valid.
+#[no_mangle]
+pub unsafe extern "C" fn load(model: *const c_char, quant: usize, quant_nf4: usize, quant_sf4: usize, fp16: bool) -> WktvStatus {
+    report((|| {
+        let model = unsafe { path_from_raw(model) }?;
+        let options = RuntimeOptions {
+            quant: QuantMap::Counts { int8: quant, nf4: quant_nf4, sf4: quant_sf4 },
+            ..RuntimeOptions::new(ModelFormat::SafeTensors, fp16)
+        };
+        let runtime = load_runtime(model, options)?;
+        replace_global(runtime);
+        Ok(())
+    })())
+}
+
+/// Load a runtime and write its handle into `runtime`.
+///
+/// # Safety
+///
+/// The caller must ensure that `model` is valid and `runtime` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn load_create(
+    model: *const c_char,
+    quant: usize,
+    quant_nf4: usize,
+    quant_sf4: usize,
+    fp16: bool,
+    runtime: *mut *mut WktvRuntime,
+) -> WktvStatus {
+    report((|| {
+        let model = unsafe { path_from_raw(model) }?;
+        let options = RuntimeOptions {
+            quant: QuantMap::Counts { int8: quant, nf4: quant_nf4, sf4: quant_sf4 },
+            ..RuntimeOptions::new(ModelFormat::SafeTensors, fp16)
+        };
+        let loaded = load_runtime(model, options)?;
+        unsafe { create_handle(runtime, loaded) }
+    })())
+}
+
+/// Release the global runtime.
+#[no_mangle]
+pub extern "C" fn release() -> WktvStatus {
+    let mut rt = RUNTIME.write().unwrap();
+    report(match rt.take() {
+        Some(_) => Ok(()),
+        None => Err(FfiError::NotLoaded.into()),
+    })
+}
+
+/// Release a runtime handle.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is either null or a live handle returned by a `*_create` loader,
+/// and that it is not used again afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn release_handle(runtime: *mut WktvRuntime) -> WktvStatus {
+    if runtime.is_null() {
+        return report(Err(FfiError::NullHandle.into()));
+    }
+    let _ = unsafe { Box::from_raw(runtime) };
+    report(Ok(()))
+}
+
+/// Load a runtime with the quantization of each layer given by `(layer, quant)` pairs.
+///
+/// # Safety
+///
+/// The caller must ensure that `model` is valid, and `layers` is valid for `len` elements.
+#[no_mangle]
+pub unsafe extern "C" fn load_with_quant_map(
+    model: *const c_char,
+    layers: *const LayerQuant,
+    len: usize,
+    fp16: bool,
+) -> WktvStatus {
+    report((|| {
+        let model = unsafe { path_from_raw(model) }?;
+        let quant = quant_from_pairs(unsafe { layers_from_raw(layers, len) }?)?;
+        let options = RuntimeOptions {
+            quant: QuantMap::Layers(quant),
+            ..RuntimeOptions::new(ModelFormat::SafeTensors, fp16)
+        };
+        let runtime = load_runtime(model, options)?;
+        replace_global(runtime);
+        Ok(())
+    })())
+}
+
+/// Load a runtime with the quantization of each layer given by `(layer, quant)` pairs, and write its handle into `runtime`.
+///
+/// Layers not listed are not quantized, and later pairs for the same layer override earlier ones.
+///
+/// # Safety
+///
+/// The caller must ensure that `model` is valid, `layers` is valid for `len` elements, and `runtime` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn load_with_quant_map_create(
+    model: *const c_char,
+    layers: *const LayerQuant,
+    len: usize,
+    fp16: bool,
+    runtime: *mut *mut WktvRuntime,
+) -> WktvStatus {
+    report((|| {
+        let model = unsafe { path_from_raw(model) }?;
+        let quant = quant_from_pairs(unsafe { layers_from_raw(layers, len) }?)?;
+        let options = RuntimeOptions {
+            quant: QuantMap::Layers(quant),
+            ..RuntimeOptions::new(ModelFormat::SafeTensors, fp16)
+        };
+        let loaded = load_runtime(model, options)?;
+        unsafe { create_handle(runtime, loaded) }
+    })())
+}
+
+/// Load a runtime with the quantization of each layer given by a spec such as `0-3:none,4-20:nf4,21-23:int8`.
+///
+/// # Safety
+///
+/// The caller must ensure that `model` and `spec` are valid.
+#[no_mangle]
+pub unsafe extern "C" fn load_with_quant_spec(model: *const c_char, spec: *const c_char, fp16: bool) -> WktvStatus {
+    report((|| {
+        let model = unsafe { path_from_raw(model) }?;
+        let quant = parse_quant_spec(&String::from_utf8_lossy(unsafe { text_from_raw(spec) }?))?;
+        let options = RuntimeOptions {
+            quant: QuantMap::Layers(quant),
+            ..RuntimeOptions::new(ModelFormat::SafeTensors, fp16)
+        };
+        let runtime = load_runtime(model, options)?;
+        replace_global(runtime);
+        Ok(())
+    })())
+}
+
+/// Load a runtime with the quantization of each layer given by a spec, and write its handle into `runtime`.
+///
+/// The spec lists comma-separated items of a layer or an inclusive layer range and one of `none`, `int8`, `nf4` or `sf4`,
+/// e.g. `0-3:none,4-20:nf4,21-23:int8`. Layers not listed are not quantized, and later items override earlier ones.
+///
+/// # Safety
+///
+/// The caller must ensure that `model` and `spec` are valid, and `runtime` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn load_with_quant_spec_create(
+    model: *const c_char,
+    spec: *const c_char,
+    fp16: bool,
+    runtime: *mut *mut WktvRuntime,
+) -> WktvStatus {
+    report((|| {
+        let model = unsafe { path_from_raw(model) }?;
+        let quant = parse_quant_spec(&String::from_utf8_lossy(unsafe { text_from_raw(spec) }?))?;
+        let options = RuntimeOptions {
+            quant: QuantMap::Layers(quant),
+            ..RuntimeOptions::new(ModelFormat::SafeTensors, fp16)
+        };
+        let loaded = load_runtime(model, options)?;
+        unsafe { create_handle(runtime, loaded) }
+    })())
+}
+
+/// Load a runtime from prefab.
+///
+/// # Safety
+///
+/// The caller must ensure that `model` is valid.
+#[no_mangle]
+pub unsafe extern "C" fn load_prefab(model: *const c_char, fp16: bool) -> WktvStatus {
+    report((|| {
+        let model = unsafe { path_from_raw(model) }?;
+        let runtime = load_runtime(model, RuntimeOptions::new(ModelFormat::Prefab, fp16))?;
+        replace_global(runtime);
+        Ok(())
+    })())
+}
+
+/// Load a runtime from prefab and write its handle into `runtime`.
+///
+/// # Safety
+///
+/// The caller must ensure that `model` is valid and `runtime` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn load_prefab_create(
+    model: *const c_char,
+    fp16: bool,
+    runtime: *mut *mut WktvRuntime,
+) -> WktvStatus {
+    report((|| {
+        let model = unsafe { path_from_raw(model) }?;
+        let loaded = load_runtime(model, RuntimeOptions::new(ModelFormat::Prefab, fp16))?;
+        unsafe { create_handle(runtime, loaded) }
+    })())
+}
+
+/// Load a runtime with `rescale` layers specified.
+///
+/// # Safety
+///
+/// The caller must ensure that `model` is valid.
+#[no_mangle]
+pub unsafe extern "C" fn load_with_rescale(
+    model: *const c_char,
+    quant: usize,
+    quant_nf4: usize,
+    quant_sf4: usize,
+    rescale: usize,
+    fp16: bool,
+) -> WktvStatus {
+    report((|| {
+        let model = unsafe { path_from_raw(model) }?;
+        let options = RuntimeOptions {
+            quant: QuantMap::Counts { int8: quant, nf4: quant_nf4, sf4: quant_sf4 },
+            rescale: Some(rescale),
+            ..RuntimeOptions::new(ModelFormat::SafeTensors, fp16)
+        };
+        let runtime = load_runtime(model, options)?;
+        replace_global(runtime);
+        Ok(())
+    })())
+}
+
+/// Load a runtime with `rescale` layers specified and write its handle into `runtime`.
+///
+/// # Safety
+///
+/// The caller must ensure that `model` is valid and `runtime` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn load_with_rescale_create(
+    model: *const c_char,
+    quant: usize,
+    quant_nf4: usize,
+    quant_sf4: usize,
+    rescale: usize,
+    fp16: bool,
+    runtime: *mut *mut WktvRuntime,
+) -> WktvStatus {
+    report((|| {
+        let model = unsafe { path_from_raw(model) }?;
+        let options = RuntimeOptions {
+            quant: QuantMap::Counts { int8: quant, nf4: quant_nf4, sf4: quant_sf4 },
+            rescale: Some(rescale),
+            ..RuntimeOptions::new(ModelFormat::SafeTensors, fp16)
+        };
+        let loaded = load_runtime(model, options)?;
+        unsafe { create_handle(runtime, loaded) }
+    })())
+}
+
+/// Load a runtime with extended hooks. Fails with `WktvStatus::InvalidArgument` for v4 and v5 models, which have none.
+///
+/// # Safety
+///
+/// The caller must ensure that `model` is valid.
+#[no_mangle]
+pub unsafe extern "C" fn load_extended(
+    model: *const c_char,
+    quant: usize,
+    quant_nf4: usize,
+    quant_sf4: usize,
+    fp16: bool,
+) -> WktvStatus {
+    report((|| {
+        let model = unsafe { path_from_raw(model) }?;
+        let options = RuntimeOptions {
+            quant: QuantMap::Counts { int8: quant, nf4: quant_nf4, sf4: quant_sf4 },
+            extended: true,
+            ..RuntimeOptions::new(ModelFormat::SafeTensors, fp16)
+        };
+        let runtime = load_runtime(model, options)?;
+        replace_global(runtime);
+        Ok(())
+    })())
+}
+
+/// Load a runtime with extended hooks and write its handle into `runtime`.
+///
+/// # Safety
+///
+/// The caller must ensure that `model` is valid and `runtime` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn load_extended_create(
+    model: *const c_char,
+    quant: usize,
+    quant_nf4: usize,
+    quant_sf4: usize,
+    fp16: bool,
+    runtime: *mut *mut WktvRuntime,
+) -> WktvStatus {
+    report((|| {
+        let model = unsafe { path_from_raw(model) }?;
+        let options = RuntimeOptions {
+            quant: QuantMap::Counts { int8: quant, nf4: quant_nf4, sf4: quant_sf4 },
+            extended: true,
+            ..RuntimeOptions::new(ModelFormat::SafeTensors, fp16)
+        };
+        let loaded = load_runtime(model, options)?;
+        unsafe { create_handle(runtime, loaded) }
+    })())
+}
+
+/// Load a runtime with `num_batch` slots that can be inferred in parallel, and write its handle into `runtime`.
+///
+/// # Safety
+///
+/// The caller must ensure that `model` is valid and `runtime` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn load_batch_create(
+    model: *const c_char,
+    quant: usize,
+    quant_nf4: usize,
+    quant_sf4: usize,
+    fp16: bool,
+    num_batch: usize,
+    runtime: *mut *mut WktvRuntime,
+) -> WktvStatus {
+    report((|| {
+        let model = unsafe { path_from_raw(model) }?;
+        let options = RuntimeOptions {
+            quant: QuantMap::Counts { int8: quant, nf4: quant_nf4, sf4: quant_sf4 },
+            num_batch,
+            ..RuntimeOptions::new(ModelFormat::SafeTensors, fp16)
+        };
+        let loaded = load_runtime(model, options)?;
+        unsafe { create_handle(runtime, loaded) }
+    })())
+}
+
+/// Load a runtime from prefab with `num_batch` slots, and write its handle into `runtime`.
+///
+/// # Safety
+///
+/// The caller must ensure that `model` is valid and `runtime` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn load_prefab_batch_create(
+    model: *const c_char,
+    fp16: bool,
+    num_batch: usize,
+    runtime: *mut *mut WktvRuntime,
+) -> WktvStatus {
+    report((|| {
+        let model = unsafe { path_from_raw(model) }?;
+        let options = RuntimeOptions {
+            num_batch,
+            ..RuntimeOptions::new(ModelFormat::Prefab, fp16)
+        };
+        let loaded = load_runtime(model, options)?;
+        unsafe { create_handle(runtime, loaded) }
+    })())
+}
+
+/// Clear the model state.
+#[no_mangle]
+pub extern "C" fn clear_state() -> WktvStatus {
+    with_global_runtime(|runtime| unsafe { clear_state_handle(runtime) })
+}
+
+/// Clear the model state of a runtime handle.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn clear_state_handle(runtime: *const WktvRuntime) -> WktvStatus {
+    unsafe { clear_state_slot(runtime, 0) }
+}
+
+/// Clear the state of one batch slot of a runtime handle.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn clear_state_slot(runtime: *const WktvRuntime, slot: usize) -> WktvStatus {
+    report((|| unsafe { runtime_from_handle(runtime) }?.clear_state(slot))())
+}
+
+/// Copy the state of batch slot `src` into batch slot `dst`, e.g. to fork a conversation.
+///
+/// The RNG of `src` is copied as well, so both slots sample alike until one is reseeded with `set_rng_state_slot`.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn copy_state_slot(runtime: *const WktvRuntime, src: usize, dst: usize) -> WktvStatus {
+    report((|| unsafe { runtime_from_handle(runtime) }?.copy_state(src, dst))())
+}
+
+/// Generate the next token prediction given the input tokens and a sampler.
+///
+/// Returns `0` on failure; check `last_error_code` to tell it apart from token `0`.
+///
+/// # Safety
+///
+/// The caller must ensure that `tokens` is valid and `len` does not exceed the actual length of `tokens`.
+#[no_mangle]
+pub unsafe extern "C" fn infer(tokens: *const u32, len: usize, sampler: Sampler) -> u32 {
+    let mut token = 0;
+    with_global_runtime(|runtime| unsafe { infer_handle(runtime, tokens, len, sampler, &mut token) });
+    token
+}
+
+/// Generate the next token prediction of a runtime handle given the input tokens and a sampler.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle, `tokens` is valid, `len` does not exceed the actual length of `tokens`,
+/// and `output` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn infer_handle(
+    runtime: *const WktvRuntime,
+    tokens: *const u32,
+    len: usize,
+    sampler: Sampler,
+    output: *mut u32,
+) -> WktvStatus {
+    unsafe { infer_with_bias_handle(runtime, tokens, len, sampler, std::ptr::null(), 0, output) }
+}
+
+/// Like `infer`, but also add `bias` to the logits of this call only; see `infer_with_bias_handle`.
+///
+/// Returns `0` on failure; check `last_error_code` to tell it apart from token `0`.
+///
+/// # Safety
+///
+/// The caller must ensure that `tokens` and `bias` are either null or valid for their lengths.
+#[no_mangle]
+pub unsafe extern "C" fn infer_with_bias(
+    tokens: *const u32,
+    len: usize,
+    sampler: Sampler,
+    bias: *const LogitBias,
+    bias_len: usize,
+) -> u32 {
+    let mut token = 0;
+    with_global_runtime(|runtime| unsafe {
+        infer_with_bias_handle(runtime, tokens, len, sampler, bias, bias_len, &mut token)
+    });
+    token
+}
+
+/// Generate the next token prediction of a runtime handle, adding `bias` to the logits on top of the slot's own bias.
+///
+/// The bias only applies to this call. A bias that bans every token fails with `InvalidArgument`.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle, `tokens` and `bias` are either null or valid for their lengths,
+/// and `output` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn infer_with_bias_handle(
+    runtime: *const WktvRuntime,
+    tokens: *const u32,
+    len: usize,
+    sampler: Sampler,
+    bias: *const LogitBias,
+    bias_len: usize,
+    output: *mut u32,
+) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { runtime_from_handle(runtime) }?;
+        let tokens = unsafe { tokens_from_raw(tokens, len) };
+        let bias = unsafe { bias_from_raw(bias, bias_len) };
+        runtime.check_bias(bias)?;
+        let token = runtime.infer(tokens, &sampler, bias)?;
+        unsafe { write_output(output, token) }
+    })())
+}
+
+/// Why a generation loop stopped.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinishReason {
+    /// `max_tokens` tokens were generated.
+    MaxTokens = 0,
+    /// One of the stop tokens was sampled.
+    StopToken = 1,
+    /// The output ended with one of the stop sequences.
+    StopSequence = 2,
+    /// The token callback returned `false`.
+    Callback = 3,
+    /// The grammar of the slot was completed.
+    Grammar = 4,
+}
+
+/// Called after each sampled token with its log-probability. Return `false` to stop generating.
+pub type OnToken = extern "C" fn(user_data: *mut c_void, token: u32, logprob: f32) -> bool;
+
+/// A token sequence passed in from C.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct TokenSequence {
+    pub tokens: *const u32,
+    pub len: usize,
+}
+
+#[derive(Debug, Default, Clone)]
+struct StopCondition<'a> {
+    max_tokens: usize,
+    tokens: &'a [u32],
+    sequences: Vec<&'a [u32]>,
+}
+
+impl StopCondition<'_> {
+    /// Collect the stop conditions passed to the generation functions.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that `tokens` and `sequences` are either null or valid for their lengths.
+    unsafe fn from_raw(
+        max_tokens: usize,
+        tokens: *const u32,
+        num_tokens: usize,
+        sequences: *const TokenSequence,
+        num_sequences: usize,
+    ) -> Self {
+        let sequences = match sequences.is_null() {
+            true => &[],
+            false => unsafe { std::slice::from_raw_parts(sequences, num_sequences) },
+        };
+        Self {
+            max_tokens,
+            tokens: unsafe { tokens_from_raw(tokens, num_tokens) },
+            sequences: sequences
+                .iter()
+                .map(|seq| unsafe { tokens_from_raw(seq.tokens, seq.len) })
+                .collect(),
+        }
+    }
+
+    fn check(&self, output: &[u32]) -> Option<FinishReason> {
+        let last = output.last()?;
+        if self.tokens.contains(last) {
+            return Some(FinishReason::StopToken);
+        }
+        if self.sequences.iter().any(|seq| !seq.is_empty() && output.ends_with(seq)) {
+            return Some(FinishReason::StopSequence);
+        }
+        None
+    }
+}
+
+/// Generate up to `max_tokens` tokens with the global runtime; see `generate_handle`.
+///
+/// # Safety
+///
+/// The caller must ensure that every pointer is valid for its length, and `output` and `reason` are valid for writes.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn generate(
+    tokens: *const u32,
+    len: usize,
+    sampler: Sampler,
+    max_tokens: usize,
+    stop_tokens: *const u32,
+    stop_count: usize,
+    stop_sequences: *const TokenSequence,
+    stop_sequence_count: usize,
+    output: *mut TokenOutput,
+    reason: *mut FinishReason,
+) -> WktvStatus {
+    with_global_runtime(|runtime| unsafe {
+        generate_handle(
+            runtime,
+            tokens,
+            len,
+            sampler,
+            max_tokens,
+            stop_tokens,
+            stop_count,
+            stop_sequences,
+            stop_sequence_count,
+            output,
+            reason,
+        )
+    })
+}
+
+/// Prefill the input tokens and keep sampling on a runtime handle in a single call.
+///
+/// Generation stops after `max_tokens` tokens, when any of `stop_tokens` is sampled,
+/// or when the output ends with any of `stop_sequences`; either stop list may be null.
+/// The generated tokens, including the one that triggered the stop, are written into `output`
+/// and fed back into the state, so the next call continues right after them.
+/// If generation fails or is cancelled, the tokens generated so far are still written into `output`.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle, every pointer is valid for its length,
+/// and `output` and `reason` are valid for writes.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn generate_handle(
+    runtime: *const WktvRuntime,
+    tokens: *const u32,
+    len: usize,
+    sampler: Sampler,
+    max_tokens: usize,
+    stop_tokens: *const u32,
+    stop_count: usize,
+    stop_sequences: *const TokenSequence,
+    stop_sequence_count: usize,
+    output: *mut TokenOutput,
+    reason: *mut FinishReason,
+) -> WktvStatus {
+    unsafe {
+        generate_with_bias_handle(
+            runtime,
+            tokens,
+            len,
+            sampler,
+            std::ptr::null(),
+            0,
+            max_tokens,
+            stop_tokens,
+            stop_count,
+            stop_sequences,
+            stop_sequence_count,
+            output,
+            reason,
+        )
+    }
+}
+
+/// Like `generate`, but also add `bias` to the logits of every token sampled by this call; see `generate_with_bias_handle`.
+///
+/// # Safety
+///
+/// The caller must ensure that every pointer is valid for its length, and `output` and `reason` are valid for writes.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn generate_with_bias(
+    tokens: *const u32,
+    len: usize,
+    sampler: Sampler,
+    bias: *const LogitBias,
+    bias_len: usize,
+    max_tokens: usize,
+    stop_tokens: *const u32,
+    stop_count: usize,
+    stop_sequences: *const TokenSequence,
+    stop_sequence_count: usize,
+    output: *mut TokenOutput,
+    reason: *mut FinishReason,
+) -> WktvStatus {
+    with_global_runtime(|runtime| unsafe {
+        generate_with_bias_handle(
+            runtime,
+            tokens,
+            len,
+            sampler,
+            bias,
+            bias_len,
+            max_tokens,
+            stop_tokens,
+            stop_count,
+            stop_sequences,
+            stop_sequence_count,
+            output,
+            reason,
+        )
+    })
+}
+
+/// Like `generate_handle`, but also add `bias` to the logits of every token sampled by this call,
+/// on top of the slot's own bias.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle, every pointer is valid for its length,
+/// and `output` and `reason` are valid for writes.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn generate_with_bias_handle(
+    runtime: *const WktvRuntime,
+    tokens: *const u32,
+    len: usize,
+    sampler: Sampler,
+    bias: *const LogitBias,
+    bias_len: usize,
+    max_tokens: usize,
+    stop_tokens: *const u32,
+    stop_count: usize,
+    stop_sequences: *const TokenSequence,
+    stop_sequence_count: usize,
+    output: *mut TokenOutput,
+    reason: *mut FinishReason,
+) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { runtime_from_handle(runtime) }?;
+        let tokens = unsafe { tokens_from_raw(tokens, len) };
+        let bias = unsafe { bias_from_raw(bias, bias_len) };
+        runtime.check_bias(bias)?;
+        if output.is_null() {
+            bail!(FfiError::NullPointer("output"));
+        }
+        if reason.is_null() {
+            bail!(FfiError::NullPointer("reason"));
+        }
+        let stop = unsafe {
+            StopCondition::from_raw(max_tokens, stop_tokens, stop_count, stop_sequences, stop_sequence_count)
+        };
+        let (tokens, finish) = runtime.generate_tokens(tokens, &sampler, bias, &stop);
+        unsafe { write_output(output, tokens.into()) }?;
+        unsafe { write_output(reason, finish?) }
+    })())
+}
+
+/// Stream generated tokens from the global runtime into a callback; see `generate_stream_handle`.
+///
+/// # Safety
+///
+/// The caller must ensure that every pointer is valid for its length, and `reason` is valid for writes.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn generate_stream(
+    tokens: *const u32,
+    len: usize,
+    sampler: Sampler,
+    max_tokens: usize,
+    stop_tokens: *const u32,
+    stop_count: usize,
+    stop_sequences: *const TokenSequence,
+    stop_sequence_count: usize,
+    on_token: Option<OnToken>,
+    user_data: *mut c_void,
+    reason: *mut FinishReason,
+) -> WktvStatus {
+    with_global_runtime(|runtime| unsafe {
+        generate_stream_handle(
+            runtime,
+            tokens,
+            len,
+            sampler,
+            max_tokens,
+            stop_tokens,
+            stop_count,
+            stop_sequences,
+            stop_sequence_count,
+            on_token,
+            user_data,
+            reason,
+        )
+    })
+}
+
+/// Like `generate_handle`, but hands each token to `on_token` as soon as it is sampled instead of collecting them.
+///
+/// `user_data` is passed through to the callback untouched. The callback runs on the calling thread;
+/// returning `false` from it stops generation with [`FinishReason::Callback`].
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle, every pointer is valid for its length,
+/// and `reason` is valid for writes.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn generate_stream_handle(
+    runtime: *const WktvRuntime,
+    tokens: *const u32,
+    len: usize,
+    sampler: Sampler,
+    max_tokens: usize,
+    stop_tokens: *const u32,
+    stop_count: usize,
+    stop_sequences: *const TokenSequence,
+    stop_sequence_count: usize,
+    on_token: Option<OnToken>,
+    user_data: *mut c_void,
+    reason: *mut FinishReason,
+) -> WktvStatus {
+    unsafe {
+        generate_stream_with_bias_handle(
+            runtime,
+            tokens,
+            len,
+            sampler,
+            std::ptr::null(),
+            0,
+            max_tokens,
+            stop_tokens,
+            stop_count,
+            stop_sequences,
+            stop_sequence_count,
+            on_token,
+            user_data,
+            reason,
+        )
+    }
+}
+
+/// Like `generate_stream`, but also add `bias` to the logits of every token sampled by this call;
+/// see `generate_stream_with_bias_handle`.
+///
+/// # Safety
+///
+/// The caller must ensure that every pointer is valid for its length, and `reason` is valid for writes.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn generate_stream_with_bias(
+    tokens: *const u32,
+    len: usize,
+    sampler: Sampler,
+    bias: *const LogitBias,
+    bias_len: usize,
+    max_tokens: usize,
+    stop_tokens: *const u32,
+    stop_count: usize,
+    stop_sequences: *const TokenSequence,
+    stop_sequence_count: usize,
+    on_token: Option<OnToken>,
+    user_data: *mut c_void,
+    reason: *mut FinishReason,
+) -> WktvStatus {
+    with_global_runtime(|runtime| unsafe {
+        generate_stream_with_bias_handle(
+            runtime,
+            tokens,
+            len,
+            sampler,
+            bias,
+            bias_len,
+            max_tokens,
+            stop_tokens,
+            stop_count,
+            stop_sequences,
+            stop_sequence_count,
+            on_token,
+            user_data,
+            reason,
+        )
+    })
+}
+
+/// Like `generate_stream_handle`, but also add `bias` to the logits of every token sampled by this call,
+/// on top of the slot's own bias.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle, every pointer is valid for its length,
+/// and `reason` is valid for writes.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn generate_stream_with_bias_handle(
+    runtime: *const WktvRuntime,
+    tokens: *const u32,
+    len: usize,
+    sampler: Sampler,
+    bias: *const LogitBias,
+    bias_len: usize,
+    max_tokens: usize,
+    stop_tokens: *const u32,
+    stop_count: usize,
+    stop_sequences: *const TokenSequence,
+    stop_sequence_count: usize,
+    on_token: Option<OnToken>,
+    user_data: *mut c_void,
+    reason: *mut FinishReason,
+) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { runtime_from_handle(runtime) }?;
+        let tokens = unsafe { tokens_from_raw(tokens, len) };
+        let bias = unsafe { bias_from_raw(bias, bias_len) };
+        runtime.check_bias(bias)?;
+        let Some(on_token) = on_token else {
+            bail!(FfiError::NullPointer("on_token"));
+        };
+        if reason.is_null() {
+            bail!(FfiError::NullPointer("reason"));
+        }
+        let stop = unsafe {
+            StopCondition::from_raw(max_tokens, stop_tokens, stop_count, stop_sequences, stop_sequence_count)
+        };
+        let finish = runtime.generate_stream(tokens, &sampler, bias, &stop, |token, logprob| {
+            on_token(user_data, token, logprob)
+        })?;
+        unsafe { write_output(reason, finish) }
+    })())
+}
+
+/// Encode the text with the global tokenizer and predict the next token with the global runtime.
+///
+/// Writes the token into `token` and its bytes into `output`.
+///
+/// # Safety
+///
+/// The caller must ensure that `text` is a valid C string, and `token` and `output` are valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn infer_text(text: *const c_char, sampler: Sampler, token: *mut u32, output: *mut TextOutput) -> WktvStatus {
+    match global_tokenizer() {
+        Ok(tokenizer) => with_global_runtime(|runtime| unsafe {
+            infer_text_handle(runtime, &*tokenizer, text, sampler, token, output)
+        }),
+        Err(err) => report(Err(err)),
+    }
+}
+
+/// Encode the text and predict the next token of a runtime handle.
+///
+/// The token is not fed back into the state, so its bytes are written out in full, together with any held back
+/// by an earlier text call, even if they end in the middle of a character.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` and `tokenizer` are live handles, `text` is a valid C string,
+/// and `token` and `output` are valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn infer_text_handle(
+    runtime: *const WktvRuntime,
+    tokenizer: *const WktvTokenizer,
+    text: *const c_char,
+    sampler: Sampler,
+    token: *mut u32,
+    output: *mut TextOutput,
+) -> WktvStatus {
+    unsafe { infer_text_with_bias_handle(runtime, tokenizer, text, sampler, std::ptr::null(), 0, token, output) }
+}
+
+/// Like `infer_text`, but also add `bias` to the logits of this call only; see `infer_text_with_bias_handle`.
+///
+/// # Safety
+///
+/// The caller must ensure that `text` is a valid C string, `bias` is either null or valid for `bias_len` elements,
+/// and `token` and `output` are valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn infer_text_with_bias(
+    text: *const c_char,
+    sampler: Sampler,
+    bias: *const LogitBias,
+    bias_len: usize,
+    token: *mut u32,
+    output: *mut TextOutput,
+) -> WktvStatus {
+    match global_tokenizer() {
+        Ok(tokenizer) => with_global_runtime(|runtime| unsafe {
+            infer_text_with_bias_handle(runtime, &*tokenizer, text, sampler, bias, bias_len, token, output)
+        }),
+        Err(err) => report(Err(err)),
+    }
+}
+
+/// Like `infer_text_handle`, but also add `bias` to the logits of this call only, on top of the slot's own bias.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` and `tokenizer` are live handles, `text` is a valid C string,
+/// `bias` is either null or valid for `bias_len` elements, and `token` and `output` are valid for writes.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn infer_text_with_bias_handle(
+    runtime: *const WktvRuntime,
+    tokenizer: *const WktvTokenizer,
+    text: *const c_char,
+    sampler: Sampler,
+    bias: *const LogitBias,
+    bias_len: usize,
+    token: *mut u32,
+    output: *mut TextOutput,
+) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { runtime_from_handle(runtime) }?;
+        let tokenizer = unsafe { tokenizer_from_handle(tokenizer) }?;
+        let text = unsafe { text_from_raw(text) }?;
+        let bias = unsafe { bias_from_raw(bias, bias_len) };
+        runtime.check_bias(bias)?;
+        if token.is_null() {
+            bail!(FfiError::NullPointer("token"));
+        }
+        if output.is_null() {
+            bail!(FfiError::NullPointer("output"));
+        }
+        let (next, text) = runtime.infer_text(tokenizer, text, &sampler, bias)?;
+        unsafe { write_output(token, next) }?;
+        unsafe { write_output(output, text.into()) }
+    })())
+}
+
+/// Continue the text with up to `max_tokens` sampled tokens, using the global tokenizer and runtime.
+///
+/// # Safety
+///
+/// The caller must ensure that `text` is a valid C string and `output` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn generate_text(
+    text: *const c_char,
+    sampler: Sampler,
+    max_tokens: usize,
+    output: *mut TextOutput,
+) -> WktvStatus {
+    match global_tokenizer() {
+        Ok(tokenizer) => with_global_runtime(|runtime| unsafe {
+            generate_text_handle(runtime, &*tokenizer, text, sampler, max_tokens, output)
+        }),
+        Err(err) => report(Err(err)),
+    }
+}
+
+/// Continue the text with up to `max_tokens` sampled tokens on a runtime handle.
+///
+/// All sampled tokens are fed back, so the state afterwards covers both the prompt and the output,
+/// and the next call continues where this one stopped. On failure or cancellation, the text so far is still written.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` and `tokenizer` are live handles, `text` is a valid C string,
+/// and `output` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn generate_text_handle(
+    runtime: *const WktvRuntime,
+    tokenizer: *const WktvTokenizer,
+    text: *const c_char,
+    sampler: Sampler,
+    max_tokens: usize,
+    output: *mut TextOutput,
+) -> WktvStatus {
+    unsafe {
+        generate_text_with_bias_handle(runtime, tokenizer, text, sampler, std::ptr::null(), 0, max_tokens, output)
+    }
+}
+
+/// Like `generate_text`, but also add `bias` to the logits of every token sampled by this call;
+/// see `generate_text_with_bias_handle`.
+///
+/// # Safety
+///
+/// The caller must ensure that `text` is a valid C string, `bias` is either null or valid for `bias_len` elements,
+/// and `output` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn generate_text_with_bias(
+    text: *const c_char,
+    sampler: Sampler,
+    bias: *const LogitBias,
+    bias_len: usize,
+    max_tokens: usize,
+    output: *mut TextOutput,
+) -> WktvStatus {
+    match global_tokenizer() {
+        Ok(tokenizer) => with_global_runtime(|runtime| unsafe {
+            generate_text_with_bias_handle(runtime, &*tokenizer, text, sampler, bias, bias_len, max_tokens, output)
+        }),
+        Err(err) => report(Err(err)),
+    }
+}
+
+/// Like `generate_text_handle`, but also add `bias` to the logits of every token sampled by this call,
+/// on top of the slot's own bias.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` and `tokenizer` are live handles, `text` is a valid C string,
+/// `bias` is either null or valid for `bias_len` elements, and `output` is valid for writes.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn generate_text_with_bias_handle(
+    runtime: *const WktvRuntime,
+    tokenizer: *const WktvTokenizer,
+    text: *const c_char,
+    sampler: Sampler,
+    bias: *const LogitBias,
+    bias_len: usize,
+    max_tokens: usize,
+    output: *mut TextOutput,
+) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { runtime_from_handle(runtime) }?;
+        let tokenizer = unsafe { tokenizer_from_handle(tokenizer) }?;
+        let text = unsafe { text_from_raw(text) }?;
+        let bias = unsafe { bias_from_raw(bias, bias_len) };
+        runtime.check_bias(bias)?;
+        if output.is_null() {
+            bail!(FfiError::NullPointer("output"));
+        }
+        let (text, result) = runtime.generate_text(tokenizer, text, &sampler, bias, max_tokens)?;
+        unsafe { write_output(output, text.into()) }?;
+        result
+    })())
+}
+
+/// A token and its log-probability.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct TokenProb {
+    pub token: u32,
+    pub logprob: f32,
+}
+
+/// A sampled token with its log-probability and the most likely alternatives, in descending order.
+///
+/// The probabilities are those of the distribution the token was drawn from, after penalties, bias and grammar masking
+/// but before truncation and temperature. Free with `free_logprobs`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct TokenLogprobs {
+    pub token: u32,
+    pub logprob: f32,
+    pub len: usize,
+    pub top: *mut TokenProb,
+}
+
+impl From<Sampled> for TokenLogprobs {
+    fn from(value: Sampled) -> Self {
+        let len = value.top.len();
+        let top = Box::into_raw(value.top.into_boxed_slice()) as *mut TokenProb;
+        TokenLogprobs {
+            token: value.token,
+            logprob: value.logprob,
+            len,
+            top,
+        }
+    }
+}
+
+/// Generate the next token with the global runtime, reporting its log-probability and the `top_n` most likely tokens.
+///
+/// # Safety
+///
+/// The caller must ensure that `tokens` is valid, `len` does not exceed the actual length of `tokens`,
+/// and `output` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn infer_logprobs(
+    tokens: *const u32,
+    len: usize,
+    sampler: Sampler,
+    top_n: usize,
+    output: *mut TokenLogprobs,
+) -> WktvStatus {
+    with_global_runtime(|runtime| unsafe { infer_logprobs_handle(runtime, tokens, len, sampler, top_n, output) })
+}
+
+/// Generate the next token of a runtime handle, reporting its log-probability and the `top_n` most likely tokens.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle, `tokens` is valid, `len` does not exceed the actual length of `tokens`,
+/// and `output` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn infer_logprobs_handle(
+    runtime: *const WktvRuntime,
+    tokens: *const u32,
+    len: usize,
+    sampler: Sampler,
+    top_n: usize,
+    output: *mut TokenLogprobs,
+) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { runtime_from_handle(runtime) }?;
+        let tokens = unsafe { tokens_from_raw(tokens, len) };
+        if output.is_null() {
+            bail!(FfiError::NullPointer("output"));
+        }
+        let sampled = runtime.infer_logprobs(tokens, &sampler, top_n)?;
+        unsafe { write_output(output, sampled.into()) }
+    })())
+}
+
+/// Delete the alternatives created by `infer_logprobs`.
+///
+/// # Safety
+///
+/// The caller must ensure that `output` was returned by `infer_logprobs` and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn free_logprobs(output: TokenLogprobs) {
+    if !output.top.is_null() {
+        let top = std::ptr::slice_from_raw_parts_mut(output.top, output.len);
+        let _ = unsafe { Box::from_raw(top) };
+    }
+}
+
+/// Logits returned by the raw infer functions.
+///
+/// The buffer is owned by the library and must be released with `free_raw`. An empty output has a null `data`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModelOutput {
+    pub len: usize,
+    pub data: *mut f32,
+}
+
+impl ModelOutput {
+    pub fn empty() -> ModelOutput {
+        ModelOutput {
+            len: 0,
+            data: std::ptr::null_mut(),
+        }
+    }
+}
+
+impl From<Vec<f32>> for ModelOutput {
+    fn from(value: Vec<f32>) -> Self {
+        let StateRaw { len, data } = value.into();
+        ModelOutput { len, data }
+    }
+}
+
+/// A flattened model state.
+///
+/// Buffers returned by `get_state` are owned by the library and must be released with `free_state`.
+/// Buffers passed to `set_state` stay owned by the caller. An empty state has a null `data`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StateRaw {
+    pub len: usize,
+    pub data: *mut f32,
+}
+
+impl StateRaw {
+    pub fn empty() -> StateRaw {
+        StateRaw {
+            len: 0,
+            data: std::ptr::null_mut(),
+        }
+    }
+}
+
+impl From<Vec<f32>> for StateRaw {
+    fn from(value: Vec<f32>) -> Self {
+        if value.is_empty() {
+            return Self::empty();
+        }
+        let len = value.len();
+        let data = Box::into_raw(value.into_boxed_slice()) as *mut f32;
+        StateRaw { len, data }
+    }
+}
+
+/// Release a buffer made by [`StateRaw::from`].
+///
+/// # Safety
+///
+/// The caller must ensure that `data` and `len` are either null or exactly as they were returned, and not yet freed.
+unsafe fn free_floats(data: *mut f32, len: usize) {
+    if !data.is_null() {
+        let data = std::ptr::slice_from_raw_parts_mut(data, len);
+        let _ = unsafe { Box::from_raw(data) };
+    }
+}
+
+/// Get the model state.
+///
+/// Returns an empty state on failure; check `last_error_code` for the reason.
+#[no_mangle]
+pub extern "C" fn get_state() -> StateRaw {
+    let mut state = StateRaw::empty();
+    with_global_runtime(|runtime| unsafe { get_state_handle(runtime, &mut state) });
+    state
+}
+
+/// Get the model state of a runtime handle.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle and `output` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn get_state_handle(runtime: *const WktvRuntime, output: *mut StateRaw) -> WktvStatus {
+    unsafe { get_state_slot(runtime, 0, output) }
+}
+
+/// Get the state of one batch slot of a runtime handle.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle and `output` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn get_state_slot(runtime: *const WktvRuntime, slot: usize, output: *mut StateRaw) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { runtime_from_handle(runtime) }?;
+        if output.is_null() {
+            bail!(FfiError::NullPointer("output"));
+        }
+        let state = runtime.get_state(slot)?;
+        unsafe { write_output(output, state.into()) }
+    })())
+}
+
+/// Query the number of floats in the state of the global runtime.
+///
+/// # Safety
+///
+/// The caller must ensure that `output` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn get_state_size(output: *mut usize) -> WktvStatus {
+    with_global_runtime(|runtime| unsafe { get_state_size_handle(runtime, output) })
+}
+
+/// Query the number of floats in the state of one batch slot of a runtime handle.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle and `output` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn get_state_size_handle(runtime: *const WktvRuntime, output: *mut usize) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { runtime_from_handle(runtime) }?;
+        unsafe { write_output(output, runtime.state.init_shape().len()) }
+    })())
+}
+
+/// Copy the model state into a caller-provided buffer.
+///
+/// # Safety
+///
+/// The caller must ensure that `output` is valid for `output_len` writes.
+#[no_mangle]
+pub unsafe extern "C" fn get_state_into(output: *mut f32, output_len: usize) -> WktvStatus {
+    with_global_runtime(|runtime| unsafe { get_state_into_slot(runtime, 0, output, output_len) })
+}
+
+/// Copy the state of slot 0 of a runtime handle into a caller-provided buffer.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle and `output` is valid for `output_len` writes.
+#[no_mangle]
+pub unsafe extern "C" fn get_state_into_handle(
+    runtime: *const WktvRuntime,
+    output: *mut f32,
+    output_len: usize,
+) -> WktvStatus {
+    unsafe { get_state_into_slot(runtime, 0, output, output_len) }
+}
+
+/// Copy the state of one batch slot into a caller-provided buffer of at least `get_state_size_handle` floats.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle and `output` is valid for `output_len` writes.
+#[no_mangle]
+pub unsafe extern "C" fn get_state_into_slot(
+    runtime: *const WktvRuntime,
+    slot: usize,
+    output: *mut f32,
+    output_len: usize,
+) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { runtime_from_handle(runtime) }?;
+        let required = runtime.state.init_shape().len();
+        let output = unsafe { floats_from_raw(output, output_len, required) }?;
+        runtime.get_state_into(slot, output)
+    })())
+}
+
+/// Free the returned state vector created by the get_state function.
+///
+/// # Safety
+///
+/// The caller must ensure that `state` was returned by one of the get_state functions and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn free_state(state: StateRaw) {
+    unsafe { free_floats(state.data, state.len) }
+}
+
+/// Set the model state.
+#[no_mangle]
+pub extern "C" fn set_state(data: StateRaw) -> WktvStatus {
+    with_global_runtime(|runtime| unsafe { set_state_handle(runtime, data) })
+}
+
+/// Set the model state of a runtime handle.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle and `data` is valid.
+#[no_mangle]
+pub unsafe extern "C" fn set_state_handle(runtime: *const WktvRuntime, data: StateRaw) -> WktvStatus {
+    unsafe { set_state_slot(runtime, 0, data) }
+}
+
+/// Set the state of one batch slot of a runtime handle.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle and `data` is valid.
+#[no_mangle]
+pub unsafe extern "C" fn set_state_slot(runtime: *const WktvRuntime, slot: usize, data: StateRaw) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { runtime_from_handle(runtime) }?;
+        if data.data.is_null() {
+            bail!(FfiError::NullPointer("state"));
+        }
+        let state = unsafe { std::slice::from_raw_parts(data.data, data.len) };
+        runtime.set_state(slot, state)
+    })())
+}
+
+/// Set the model state from half-precision values.
+///
+/// # Safety
+///
+/// The caller must ensure that `data` is valid and `len` does not exceed its actual length.
+#[no_mangle]
+pub unsafe extern "C" fn set_state_f16(data: *const f16, len: usize) -> WktvStatus {
+    with_global_runtime(|runtime| unsafe { set_state_f16_slot(runtime, 0, data, len) })
+}
+
+/// Set the model state of a runtime handle from half-precision values.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle, `data` is valid and `len` does not exceed its actual length.
+#[no_mangle]
+pub unsafe extern "C" fn set_state_f16_handle(runtime: *const WktvRuntime, data: *const f16, len: usize) -> WktvStatus {
+    unsafe { set_state_f16_slot(runtime, 0, data, len) }
+}
+
+/// Set the state of one batch slot from half-precision values, e.g. an fp16 snapshot of `get_state_slot`.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle, `data` is valid and `len` does not exceed its actual length.
+#[no_mangle]
+pub unsafe extern "C" fn set_state_f16_slot(
+    runtime: *const WktvRuntime,
+    slot: usize,
+    data: *const f16,
+    len: usize,
+) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { runtime_from_handle(runtime) }?;
+        if data.is_null() {
+            bail!(FfiError::NullPointer("state"));
+        }
+        let state = unsafe { std::slice::from_raw_parts(data, len) };
+        let state = state.iter().map(|x| x.to_f32()).collect_vec();
+        runtime.set_state(slot, &state)
+    })())
 }
 
-/// Initialize logger and RNG. Call this once before everything.
+/// Delete the model output vector created by the infer functions.
+///
+/// # Safety
+///
+/// The caller must ensure that `output` was returned by one of the raw infer functions and not yet freed.
 #[no_mangle]
-pub extern "C" fn init(seed: u64) {
-    let _ = simple_logger::SimpleLogger::new()
-        .with_level(log::LevelFilter::Warn)
-        .with_module_level("web_rwkv", log::LevelFilter::Info)
-        .with_module_level("web_rwkv_ffi", log::LevelFilter::Info)
-        .init();
-    fastrand::seed(seed);
+pub unsafe extern "C" fn free_raw(output: ModelOutput) {
+    unsafe { free_floats(output.data, output.len) }
 }
 
-/// Set the RNG seed.
+/// Compute the model's raw output (next token prediction only) given the input tokens.
+///
+/// Returns an empty output on failure; check `last_error_code` for the reason.
+///
+/// # Safety
+///
+/// The caller must ensure that `tokens` is valid and `len` does not exceed the actual length of `tokens`.
 #[no_mangle]
-pub extern "C" fn seed(seed: u64) {
-    fastrand::seed(seed);
+pub unsafe extern "C" fn infer_raw_last(tokens: *const u32, len: usize) -> ModelOutput {
+    let mut output = ModelOutput::empty();
+    with_global_runtime(|runtime| unsafe { infer_raw_last_handle(runtime, tokens, len, &mut output) });
+    output
 }
 
-/// Load a runtime.
+/// Compute the raw output (next token prediction only) of a runtime handle given the input tokens.
 ///
 /// # Safety
 ///
-/// The caller must ensure that `model` is valid.
+/// The caller must ensure that `runtime` is a live handle, `tokens` is valid, `len` does not exceed the actual length of `tokens`,
+/// and `output` is valid for writes.
 #[no_mangle]
-pub unsafe extern "C" fn load(model: *const c_char, quant: usize, quant_nf4: usize, quant_sf4: usize, fp16: bool) {
-    let model = unsafe { CStr::from_ptr(model).to_string_lossy().to_string() };
-    match load_runtime(model, quant, quant_nf4, quant_sf4,None, false, fp16) {
-        Ok(runtime) => {
-            let mut rt = RUNTIME.write().unwrap();
-            rt.replace(runtime);
+pub unsafe extern "C" fn infer_raw_last_handle(
+    runtime: *const WktvRuntime,
+    tokens: *const u32,
+    len: usize,
+    output: *mut ModelOutput,
+) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { runtime_from_handle(runtime) }?;
+        if output.is_null() {
+            bail!(FfiError::NullPointer("output"));
         }
-        Err(err) => log::error!("{err}"),
-    }
+        let tokens = unsafe { tokens_from_raw(tokens, len) };
+        let logits = runtime.infer_raw_last(tokens)?;
+        unsafe { write_output(output, logits.into()) }
+    })())
 }
 
+/// Compute the model's raw output (next token prediction only) into a caller-provided buffer.
+///
+/// # Safety
+///
+/// The caller must ensure that `tokens` is valid, `len` does not exceed the actual length of `tokens`,
+/// and `output` is valid for `output_len` writes.
 #[no_mangle]
-pub unsafe extern "C" fn release() {
-    let runtime = {
-        let runtime = RUNTIME.read().unwrap();
-        let Some(runtime) = runtime.clone() else {
-            log::error!("runtime not loaded");
-            return;
-        };
-        runtime
-    };
-    let _ = runtime.runtime;
-    let _ = runtime.context;
-    let _ = runtime.tokio;
-    let _ = runtime.state;
-    let mut rt = RUNTIME.write().unwrap();
-    rt.take();
+pub unsafe extern "C" fn infer_raw_last_into(
+    tokens: *const u32,
+    len: usize,
+    output: *mut f32,
+    output_len: usize,
+) -> WktvStatus {
+    with_global_runtime(|runtime| unsafe { infer_raw_last_into_handle(runtime, tokens, len, output, output_len) })
 }
 
-/// Load a runtime from prefab.
-/// 
+/// Compute the raw output (next token prediction only) of a runtime handle into a caller-provided buffer.
+///
+/// The buffer must hold at least `num_vocab` values; the first `num_vocab` of them are overwritten.
+/// A shorter buffer fails with `WktvStatus::ShapeMismatch` before the state is touched.
+///
 /// # Safety
-/// 
-/// The caller must ensure that `model` is valid.
+///
+/// The caller must ensure that `runtime` is a live handle, `tokens` is valid, `len` does not exceed the actual length of `tokens`,
+/// and `output` is valid for `output_len` writes.
 #[no_mangle]
-pub unsafe extern "C" fn load_prefab(model: *const c_char, fp16: bool) {
-    let model = unsafe { CStr::from_ptr(model).to_string_lossy().to_string() };
-    match load_runtime_prefab(model, fp16) {
-        Ok(runtime) => {
-            let mut rt = RUNTIME.write().unwrap();
-            rt.replace(runtime);
-        }
-        Err(err) => log::error!("{err}"),
-    }
+pub unsafe extern "C" fn infer_raw_last_into_handle(
+    runtime: *const WktvRuntime,
+    tokens: *const u32,
+    len: usize,
+    output: *mut f32,
+    output_len: usize,
+) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { runtime_from_handle(runtime) }?;
+        let output = unsafe { floats_from_raw(output, output_len, runtime.info.num_vocab) }?;
+        let tokens = unsafe { tokens_from_raw(tokens, len) };
+        runtime.infer_raw_last_into(tokens, output)
+    })())
 }
 
-/// Load a runtime with `rescale` layers specified.
+/// Compute the model's raw output (predictions of all tokens) into a caller-provided buffer.
 ///
 /// # Safety
 ///
-/// The caller must ensure that `model` is valid.
+/// The caller must ensure that `tokens` is valid, `len` does not exceed the actual length of `tokens`,
+/// and `output` is valid for `output_len` writes.
 #[no_mangle]
-pub unsafe extern "C" fn load_with_rescale(
-    model: *const c_char,
-    quant: usize,
-    quant_nf4: usize,
-    quant_sf4: usize,
-    rescale: usize,
-    fp16: bool,
-) {
-    let model = unsafe { CStr::from_ptr(model).to_string_lossy().to_string() };
-    match load_runtime(model, quant, quant_nf4, quant_sf4, Some(rescale), false, fp16) {
-        Ok(runtime) => {
-            let mut rt = RUNTIME.write().unwrap();
-            rt.replace(runtime);
-        }
-        Err(err) => log::error!("{err}"),
-    }
+pub unsafe extern "C" fn infer_raw_full_into(
+    tokens: *const u32,
+    len: usize,
+    output: *mut f32,
+    output_len: usize,
+) -> WktvStatus {
+    with_global_runtime(|runtime| unsafe { infer_raw_full_into_handle(runtime, tokens, len, output, output_len) })
 }
 
-/// Load a runtime with extended hooks.
+/// Compute the raw output (predictions of all tokens) of a runtime handle into a caller-provided buffer.
+///
+/// The buffer must hold at least `len * num_vocab` values, see `get_output_size_handle`.
+/// A shorter buffer fails with `WktvStatus::ShapeMismatch` before the state is touched.
 ///
 /// # Safety
 ///
-/// The caller must ensure that `model` is valid.
+/// The caller must ensure that `runtime` is a live handle, `tokens` is valid, `len` does not exceed the actual length of `tokens`,
+/// and `output` is valid for `output_len` writes.
 #[no_mangle]
-pub unsafe extern "C" fn load_extended(
-    model: *const c_char,
-    quant: usize,
-    quant_nf4: usize,
-    quant_sf4: usize,
-    fp16: bool,
-) {
-    let model = unsafe { CStr::from_ptr(model).to_string_lossy().to_string() };
-    match load_runtime(model, quant, quant_nf4, quant_sf4, None, true, fp16) {
-        Ok(runtime) => {
-            let mut rt = RUNTIME.write().unwrap();
-            rt.replace(runtime);
-        }
-        Err(err) => log::error!("{err}"),
-    }
+pub unsafe extern "C" fn infer_raw_full_into_handle(
+    runtime: *const WktvRuntime,
+    tokens: *const u32,
+    len: usize,
+    output: *mut f32,
+    output_len: usize,
+) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { runtime_from_handle(runtime) }?;
+        let required = runtime.output_size(len, RnnOption::Full)?;
+        let output = unsafe { floats_from_raw(output, output_len, required) }?;
+        let tokens = unsafe { tokens_from_raw(tokens, len) };
+        runtime.infer_raw_full_into(tokens, output)
+    })())
 }
 
-/// Clear the model state.
+/// Query the number of floats the raw infer functions of the global runtime output for `len` tokens.
+///
+/// # Safety
+///
+/// The caller must ensure that `output` is valid for writes.
 #[no_mangle]
-pub extern "C" fn clear_state() {
-    let runtime = {
-        let runtime = RUNTIME.read().unwrap();
-        let Some(runtime) = runtime.clone() else {
-            log::error!("runtime not loaded");
-            return;
-        };
-        runtime
-    };
-    let tensor = runtime.state.init();
-    let _ = runtime.state.load(tensor, 0);
+pub unsafe extern "C" fn get_output_size(len: usize, option: u32, output: *mut usize) -> WktvStatus {
+    with_global_runtime(|runtime| unsafe { get_output_size_handle(runtime, len, option, output) })
 }
 
-/// Generate the next token prediction given the input tokens and a sampler.
+/// Query the number of floats the raw infer functions of a runtime handle output for `len` tokens:
+/// `num_vocab` for `InferOption::Last`, and `len * num_vocab` for `InferOption::Full`.
+/// `option` must be one of [`InferOption`].
 ///
 /// # Safety
 ///
-/// The caller must ensure that `tokens` is valid and `len` does not exceed the actual length of `tokens`.
+/// The caller must ensure that `runtime` is a live handle and `output` is valid for writes.
 #[no_mangle]
-pub unsafe extern "C" fn infer(tokens: *const u32, len: usize, sampler: Sampler) -> u32 {
-    let runtime = {
-        let runtime = RUNTIME.read().unwrap();
-        let Some(runtime) = runtime.clone() else {
-            log::error!("runtime not loaded");
-            return 0;
-        };
-        runtime
-    };
+pub unsafe extern "C" fn get_output_size_handle(
+    runtime: *const WktvRuntime,
+    len: usize,
+    option: u32,
+    output: *mut usize,
+) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { runtime_from_handle(runtime) }?;
+        let option = InferOption::try_from(option)?;
+        unsafe { write_output(output, runtime.output_size(len, option.into())?) }
+    })())
+}
 
-    let tokens: Vec<Token> = unsafe { std::slice::from_raw_parts(tokens, len) }.iter().map(|t| Token::Token(*t)).collect();
-    if tokens.is_empty() {
-        log::error!("input cannot be empty");
-        return 0;
-    }
-
-    let tokio = runtime.tokio.clone();
-    tokio.block_on(async move {
-        let context = &runtime.context;
-        let mut inference = Some(RnnInput::new(
-            vec![RnnInputBatch {
-                tokens,
-                option: RnnOption::Last,
-            }],
-            128,
-        ));
-        let output = loop {
-            let input = inference.take().unwrap();
-            let (input, output) = match runtime.runtime.infer(input).await {
-                Ok(result) => result,
-                Err(err) => {
-                    log::error!("Inference error: {err}");
-                    return 0;
-                }
-            };
-            let output = output[0].0.clone();
+/// Compute the model's raw output (predictions of all tokens) given the input tokens.
+///
+/// Returns an empty output on failure; check `last_error_code` for the reason.
+///
+/// # Safety
+///
+/// The caller must ensure that `tokens` is valid and `len` does not exceed the actual length of `tokens`.
+#[no_mangle]
+pub unsafe extern "C" fn infer_raw_full(tokens: *const u32, len: usize) -> ModelOutput {
+    let mut output = ModelOutput::empty();
+    with_global_runtime(|runtime| unsafe { infer_raw_full_handle(runtime, tokens, len, &mut output) });
+    output
+}
 
-            if input.batches[0].tokens.is_empty() {
-                if sampler.top_k > 1 {
-                    let output = softmax_one(context, output).await.expect("softmax failed");
-                    break output.to_vec();
-                } else {
-                    break output.to_vec();
-                }
-            }
-            inference.replace(input);
-        };
-        if sampler.top_k > 1 {
-            sampler.sample(&output)
-        } else {
-            output
-                .iter()
-                .enumerate()
-                .max_by(|(_, x), (_, y)| x.total_cmp(y))
-                .unwrap()
-                .0 as u32
+/// Compute the raw output (predictions of all tokens) of a runtime handle given the input tokens.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle, `tokens` is valid, `len` does not exceed the actual length of `tokens`,
+/// and `output` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn infer_raw_full_handle(
+    runtime: *const WktvRuntime,
+    tokens: *const u32,
+    len: usize,
+    output: *mut ModelOutput,
+) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { runtime_from_handle(runtime) }?;
+        if output.is_null() {
+            bail!(FfiError::NullPointer("output"));
         }
-
-    })
+        let tokens = unsafe { tokens_from_raw(tokens, len) };
+        let logits = runtime.infer_raw_full(tokens)?;
+        unsafe { write_output(output, logits.into()) }
+    })())
 }
 
+/// Which predictions a batch slot outputs.
 #[repr(C)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct ModelOutput {
-    pub len: usize,
-    pub data: *mut f32,
+pub enum InferOption {
+    /// Only output the prediction for the last token.
+    Last = 0,
+    /// Output predictions for all tokens.
+    Full = 1,
 }
 
-impl ModelOutput {
-    pub fn empty() -> ModelOutput {
-        ModelOutput::from(vec![])
+impl TryFrom<u32> for InferOption {
+    type Error = FfiError;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(InferOption::Last),
+            1 => Ok(InferOption::Full),
+            _ => Err(FfiError::InvalidArgument("unknown infer option")),
+        }
     }
 }
 
-impl From<Vec<f32>> for ModelOutput {
-    fn from(value: Vec<f32>) -> Self {
-        let mut value = std::mem::ManuallyDrop::new(value);
-        let len = value.len();
-        let data = value.as_mut_ptr();
-        ModelOutput { data, len }
+impl From<InferOption> for RnnOption {
+    fn from(value: InferOption) -> Self {
+        match value {
+            InferOption::Last => RnnOption::Last,
+            InferOption::Full => RnnOption::Full,
+        }
     }
 }
+
+/// Input of one batch slot.
 #[repr(C)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct StateRaw {
+#[derive(Debug, Clone, Copy)]
+pub struct InferBatch {
+    pub tokens: *const u32,
     pub len: usize,
-    pub data: *mut f32,
+    /// One of [`InferOption`].
+    pub option: u32,
 }
 
-impl StateRaw {
-    pub fn empty() -> StateRaw {
-        StateRaw::from(vec![])
+/// Advance every batch slot of a runtime handle in the same dispatches, writing the raw output of slot `i` into `outputs[i]`.
+/// Slots with no tokens keep their state and yield an empty output.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle, `inputs` and `outputs` both point to `num_batch` elements,
+/// and every token array in `inputs` is valid.
+#[no_mangle]
+pub unsafe extern "C" fn infer_batch(
+    runtime: *const WktvRuntime,
+    inputs: *const InferBatch,
+    num_batch: usize,
+    outputs: *mut ModelOutput,
+) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { runtime_from_handle(runtime) }?;
+        if inputs.is_null() {
+            bail!(FfiError::NullPointer("inputs"));
+        }
+        if outputs.is_null() {
+            bail!(FfiError::NullPointer("outputs"));
+        }
+        let inputs = unsafe { std::slice::from_raw_parts(inputs, num_batch) };
+        let batches = inputs
+            .iter()
+            .map(|input| {
+                let option = InferOption::try_from(input.option)?;
+                let tokens = unsafe { tokens_from_raw(input.tokens, input.len) };
+                Ok(RnnInputBatch::new(tokens.to_vec(), option.into()))
+            })
+            .collect::<Result<_>>()?;
+        let logits = runtime.infer_batch(batches)?;
+
+        let outputs = unsafe { std::slice::from_raw_parts_mut(outputs, num_batch) };
+        for (output, logits) in outputs.iter_mut().zip(logits) {
+            *output = logits.into();
+        }
+        Ok(())
+    })())
+}
+
+/// Repetition penalties applied to the logits before sampling, following the RWKV reference runner.
+///
+/// Each sampled token `t` lowers its own logit in later picks by `presence + frequency * count[t]`.
+/// After every pick all counts are multiplied by `decay`, so old repetitions are gradually forgiven.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Penalty {
+    pub presence: f32,
+    pub frequency: f32,
+    pub decay: f32,
+}
+
+impl Default for Penalty {
+    fn default() -> Self {
+        Self {
+            presence: 0.0,
+            frequency: 0.0,
+            decay: 1.0,
+        }
     }
 }
 
-impl From<Vec<f32>> for StateRaw {
-    fn from(value: Vec<f32>) -> Self {
-        let mut value = std::mem::ManuallyDrop::new(value);
-        let len = value.len();
-        let data = value.as_mut_ptr();
-        StateRaw { data, len }
+impl Penalty {
+    fn apply(&self, logits: &mut [f32], occurrence: &HashMap<u32, f32>) {
+        for (&token, &count) in occurrence {
+            if let Some(logit) = logits.get_mut(token as usize) {
+                *logit -= self.presence + self.frequency * count;
+            }
+        }
+    }
+
+    fn update(&self, occurrence: &mut HashMap<u32, f32>, token: u32) {
+        occurrence.values_mut().for_each(|count| *count *= self.decay);
+        *occurrence.entry(token).or_default() += 1.0;
     }
 }
 
-/// Get the model state.
+/// Set the repetition penalties of the global runtime.
 #[no_mangle]
-pub extern "C" fn get_state() -> StateRaw {
-    let runtime = {
-        let runtime = RUNTIME.read().unwrap();
-        let Some(runtime) = runtime.clone() else {
-            log::error!("runtime not loaded");
-            return StateRaw::empty();
-        };
-        runtime
-    };
-    let tokio = runtime.tokio.clone();
-    let tensor = tokio.block_on(async move {
-        runtime.state.back(0).await.map_err(|err| log::error!("{err}"))
-    }).unwrap();
-    tensor.to_vec().into()
+pub extern "C" fn set_penalty(penalty: Penalty) -> WktvStatus {
+    with_global_runtime(|runtime| unsafe { set_penalty_handle(runtime, penalty) })
 }
 
-/// Free the returned state vector created by the get_state function.
+/// Set the repetition penalties of a runtime handle.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle.
 #[no_mangle]
-pub extern "C" fn free_state(state: StateRaw) {
-    let x = unsafe { std::slice::from_raw_parts_mut(state.data, state.len) };
-    let x = x.as_mut_ptr();
-    let _ = unsafe { Box::from_raw(x) };
+pub unsafe extern "C" fn set_penalty_handle(runtime: *const WktvRuntime, penalty: Penalty) -> WktvStatus {
+    unsafe { set_penalty_slot(runtime, 0, penalty) }
 }
 
-/// Set the model state.
+/// Set the repetition penalties of one batch slot.
+///
+/// The penalties stay in effect across calls; the token counts they are based on are reset by `clear_state`.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle.
 #[no_mangle]
-pub extern "C" fn set_state(data: StateRaw) {
-    let runtime = {
-        let runtime = RUNTIME.read().unwrap();
-        let Some(runtime) = runtime.clone() else {
-            log::error!("runtime not loaded");
-            return;
-        };
-        runtime
-    };
-    let tokio = runtime.tokio.clone();
-    tokio.block_on(async move {
-            let shape = runtime.state.init_shape();
-            let state = unsafe { std::slice::from_raw_parts(data.data, data.len) };
-            let state: web_rwkv::tensor::Tensor<web_rwkv::tensor::Cpu<f32>, f32> = runtime.context.tensor_from_data(shape, state.to_vec()).unwrap();
-            let _ = runtime.state.load(state, 0);
-        },
-    );
+pub unsafe extern "C" fn set_penalty_slot(runtime: *const WktvRuntime, slot: usize, penalty: Penalty) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { runtime_from_handle(runtime) }?;
+        runtime.set_penalty(slot, penalty)
+    })())
 }
 
-/// Delete the model output vector created by the infer functions.
+/// An additive bias on the logit of one token. A bias of negative infinity bans the token.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct LogitBias {
+    pub token: u32,
+    pub bias: f32,
+}
+
+/// Set the logit bias of the global runtime; see `set_logit_bias_slot`.
+///
+/// # Safety
+///
+/// The caller must ensure that `bias` is either null or valid for `len` elements.
 #[no_mangle]
-pub extern "C" fn free_raw(output: ModelOutput) {
-    let x = unsafe { std::slice::from_raw_parts_mut(output.data, output.len) };
-    let x = x.as_mut_ptr();
-    let _ = unsafe { Box::from_raw(x) };
+pub unsafe extern "C" fn set_logit_bias(bias: *const LogitBias, len: usize) -> WktvStatus {
+    with_global_runtime(|runtime| unsafe { set_logit_bias_slot(runtime, 0, bias, len) })
 }
 
-/// Compute the model's raw output (next token prediction only) given the input tokens.
+/// Set the logit bias of a runtime handle; see `set_logit_bias_slot`.
 ///
 /// # Safety
 ///
-/// The caller must ensure that `tokens` is valid and `len` does not exceed the actual length of `tokens`.
+/// The caller must ensure that `runtime` is a live handle and `bias` is either null or valid for `len` elements.
 #[no_mangle]
-pub unsafe extern "C" fn infer_raw_last(tokens: *const u32, len: usize) -> ModelOutput {
-    let runtime = {
-        let runtime = RUNTIME.read().unwrap();
-        let Some(runtime) = runtime.clone() else {
-            log::error!("runtime not loaded");
-            return ModelOutput::empty();
-        };
-        runtime
-    };
+pub unsafe extern "C" fn set_logit_bias_handle(runtime: *const WktvRuntime, bias: *const LogitBias, len: usize) -> WktvStatus {
+    unsafe { set_logit_bias_slot(runtime, 0, bias, len) }
+}
 
-    let tokens: Vec<Token> = unsafe { std::slice::from_raw_parts(tokens, len) }.iter().map(|t| Token::Token(*t)).collect();
-    if tokens.is_empty() {
-        log::error!("input cannot be empty");
-        return ModelOutput::empty();
-    }
-
-    let tokio = runtime.tokio.clone();
-    let output = tokio.block_on(async move {
-        let mut inference = Some(RnnInput::new(
-            vec![RnnInputBatch {
-                tokens: tokens.to_vec(),
-                option: RnnOption::Last,
-            }],
-            128,
-        ));
-        loop {
-            let input = inference.take().unwrap();
-            let (input, output) = match runtime.runtime.infer(input).await {
-                Ok(result) => result,
-                Err(err) => {
-                    log::error!("Inference error: {err}");
-                    break vec![];
-                }
-            };
-            let output = output[0].0.clone();
+/// Replace the logit bias of one batch slot, which every sampling call on the slot applies before the softmax.
+///
+/// Later entries for the same token win. Pass an empty list to remove all biases.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle and `bias` is either null or valid for `len` elements.
+#[no_mangle]
+pub unsafe extern "C" fn set_logit_bias_slot(
+    runtime: *const WktvRuntime,
+    slot: usize,
+    bias: *const LogitBias,
+    len: usize,
+) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { runtime_from_handle(runtime) }?;
+        let bias = unsafe { bias_from_raw(bias, len) };
+        runtime.set_logit_bias(slot, bias)
+    })())
+}
 
-            if input.batches[0].tokens.is_empty() {
-                break output.to_vec();
-            }
-            inference.replace(input);
-        }
-    });
+/// Constrain the global runtime to a grammar, using the global tokenizer; see `set_grammar_slot`.
+///
+/// # Safety
+///
+/// The caller must ensure that `grammar` is either null or a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn set_grammar(grammar: *const WktvGrammar) -> WktvStatus {
+    let tokenizer = match grammar.is_null() {
+        true => None,
+        false => match global_tokenizer() {
+            Ok(tokenizer) => Some(tokenizer),
+            Err(err) => return report(Err(err)),
+        },
+    };
+    let tokenizer = tokenizer.as_deref().map_or(std::ptr::null(), |x| x as *const _);
+    with_global_runtime(|runtime| unsafe { set_grammar_slot(runtime, tokenizer, 0, grammar) })
+}
 
-    output.into()
+/// Constrain a runtime handle to a grammar; see `set_grammar_slot`.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle, and `tokenizer` and `grammar` are either null or live handles.
+#[no_mangle]
+pub unsafe extern "C" fn set_grammar_handle(
+    runtime: *const WktvRuntime,
+    tokenizer: *const WktvTokenizer,
+    grammar: *const WktvGrammar,
+) -> WktvStatus {
+    unsafe { set_grammar_slot(runtime, tokenizer, 0, grammar) }
 }
 
-/// Compute the model's raw output (predictions of all tokens) given the input tokens.
+/// Constrain every token sampled on one batch slot to keep the output a prefix of a sentence of the grammar.
+///
+/// The tokenizer supplies the bytes of each token and must match the model's vocabulary.
+/// Once the output is a complete sentence, the end-of-text token `0` is also allowed;
+/// generation stops with `FinishReason::Grammar` when that token is sampled or nothing else can follow.
+/// The match restarts from the beginning of the grammar here and on `clear_state`. Pass a null grammar to remove it.
 ///
 /// # Safety
 ///
-/// The caller must ensure that `tokens` is valid and `len` does not exceed the actual length of `tokens`.
+/// The caller must ensure that `runtime` is a live handle, and `tokenizer` and `grammar` are either null or live handles.
 #[no_mangle]
-pub unsafe extern "C" fn infer_raw_full(tokens: *const u32, len: usize) -> ModelOutput {
-    let runtime = {
-        let runtime = RUNTIME.read().unwrap();
-        let Some(runtime) = runtime.clone() else {
-            log::error!("runtime not loaded");
-            return ModelOutput::empty();
+pub unsafe extern "C" fn set_grammar_slot(
+    runtime: *const WktvRuntime,
+    tokenizer: *const WktvTokenizer,
+    slot: usize,
+    grammar: *const WktvGrammar,
+) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { runtime_from_handle(runtime) }?;
+        let grammar = match unsafe { grammar.as_ref() } {
+            Some(grammar) => {
+                let tokenizer = unsafe { tokenizer_from_handle(tokenizer) }?;
+                Some((grammar.0.clone(), tokenizer.vocab()))
+            }
+            None => None,
         };
-        runtime
-    };
+        runtime.set_grammar(slot, grammar)
+    })())
+}
 
-    let tokens: Vec<Token> = unsafe { std::slice::from_raw_parts(tokens, len) }.iter().map(|t| Token::Token(*t)).collect();
-    if tokens.is_empty() {
-        log::error!("input cannot be empty");
-        return ModelOutput::empty();
-    }
-
-    let tokio = runtime.tokio.clone();
-    let output = tokio.block_on(async move {
-        let mut inference = Some(RnnInput::new(
-            vec![RnnInputBatch {
-                tokens: tokens.to_vec(),
-                option: RnnOption::Full,
-            }],
-            128,
-        ));
-        let mut outputs = vec![];
-        loop {
-            let input = inference.take().unwrap();
-            let (input, output) = match runtime.runtime.infer(input).await {
-                Ok(result) => result,
-                Err(err) => {
-                    log::error!("Inference error: {err}");
-                    break;
-                }
-            };
-            let mut output = output[0].0.clone().to_vec();
-            outputs.append(&mut output);
+/// Select the sampling strategy of the global runtime; see `set_sampler_config_slot`.
+///
+/// # Safety
+///
+/// The caller must ensure that `config` is either null or valid.
+#[no_mangle]
+pub unsafe extern "C" fn set_sampler_config(config: *const SamplerConfig) -> WktvStatus {
+    with_global_runtime(|runtime| unsafe { set_sampler_config_slot(runtime, 0, config) })
+}
 
-            if input.batches[0].tokens.is_empty() {
-                break;
-            }
-            inference.replace(input);
-        }
-        outputs
-    });
+/// Select the sampling strategy of a runtime handle; see `set_sampler_config_slot`.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle and `config` is either null or valid.
+#[no_mangle]
+pub unsafe extern "C" fn set_sampler_config_handle(runtime: *const WktvRuntime, config: *const SamplerConfig) -> WktvStatus {
+    unsafe { set_sampler_config_slot(runtime, 0, config) }
+}
 
-    output.into()
+/// Select the sampling strategy of one batch slot.
+///
+/// Once set, the config is used instead of the `Sampler` passed to each sampling call on this slot;
+/// pass null to go back to the per-call `Sampler`. Setting a config also restarts mirostat from `2 * tau`.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle and `config` is either null or valid.
+#[no_mangle]
+pub unsafe extern "C" fn set_sampler_config_slot(
+    runtime: *const WktvRuntime,
+    slot: usize,
+    config: *const SamplerConfig,
+) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { runtime_from_handle(runtime) }?;
+        let config = unsafe { config.as_ref() }.copied();
+        runtime.set_sampler_config(slot, config)
+    })())
 }
 
 #[repr(C)]
@@ -844,7 +3693,7 @@ impl Default for Sampler {
 }
 
 impl Sampler {
-    pub fn sample(&self, probs: &[f32]) -> u32 {
+    pub fn sample(&self, probs: &[f32], rng: &mut fastrand::Rng) -> u32 {
         let sorted: Vec<_> = probs
             .iter()
             .copied()
@@ -872,7 +3721,7 @@ impl Sampler {
             })
             .collect();
 
-        let rand = fastrand::f32();
+        let rand = rng.f32();
         let token = sorted
             .into_iter()
             .find_or_first(|&(_, cum)| rand <= cum)
@@ -891,6 +3740,16 @@ pub struct ModelInfoOutput {
     pub num_emb: usize,
     pub num_vocab: usize,
     pub num_head: usize,
+    /// The format the model was loaded from.
+    pub format: ModelFormat,
+    /// Float type of the activations. Weights that are not quantized are always stored in f16.
+    pub precision: Precision,
+    /// Number of layers stored in Int8; see `get_layer_quant` for which ones.
+    pub num_int8: usize,
+    /// Number of layers stored in NF4.
+    pub num_nf4: usize,
+    /// Number of layers stored in SF4.
+    pub num_sf4: usize,
 }
 
 impl Default for ModelInfoOutput {
@@ -902,33 +3761,70 @@ impl Default for ModelInfoOutput {
             num_emb: 0,
             num_vocab: 0,
             num_head: 0,
+            format: ModelFormat::SafeTensors,
+            precision: Precision::F32,
+            num_int8: 0,
+            num_nf4: 0,
+            num_sf4: 0,
         }
     }
 }
 
+/// Returns the model info.
+///
+/// Returns all zeros on failure; check `last_error_code` for the reason.
 #[no_mangle]
-pub unsafe extern "C" fn get_model_info() -> ModelInfoOutput {
-    let runtime = {
-        let runtime = RUNTIME.read().unwrap();
-        let Some(runtime) = runtime.clone() else {
-            log::error!("runtime not loaded");
-            return ModelInfoOutput::default();
-        };
-        runtime
-    };
+pub extern "C" fn get_model_info() -> ModelInfoOutput {
+    let mut info = ModelInfoOutput::default();
+    with_global_runtime(|runtime| unsafe { get_model_info_handle(runtime, &mut info) });
+    info
+}
 
-    let info = runtime.info;
-    ModelInfoOutput {
-        version: match info.version {
-            ModelVersion::V4 => 4,
-            ModelVersion::V5 => 5,
-            ModelVersion::V6 => 6,
-            ModelVersion::V7 => 7,
-        },
-        num_layer: info.num_layer,
-        num_hidden: info.num_hidden,
-        num_emb: info.num_emb,
-        num_vocab: info.num_vocab,
-        num_head: info.num_head,
-    }
+/// Returns the model info of a runtime handle.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle and `output` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn get_model_info_handle(runtime: *const WktvRuntime, output: *mut ModelInfoOutput) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { runtime_from_handle(runtime) }?;
+        unsafe { write_output(output, runtime.model_info()) }
+    })())
+}
+
+/// Write how each layer of the global model is stored into `output`, which must hold `num_layer` values.
+///
+/// # Safety
+///
+/// The caller must ensure that `output` is valid for `len` writes.
+#[no_mangle]
+pub unsafe extern "C" fn get_layer_quant(output: *mut QuantKind, len: usize) -> WktvStatus {
+    with_global_runtime(|runtime| unsafe { get_layer_quant_handle(runtime, output, len) })
+}
+
+/// Write how each layer of a runtime's model is stored into `output`, which must hold `num_layer` values.
+///
+/// # Safety
+///
+/// The caller must ensure that `runtime` is a live handle and `output` is valid for `len` writes.
+#[no_mangle]
+pub unsafe extern "C" fn get_layer_quant_handle(
+    runtime: *const WktvRuntime,
+    output: *mut QuantKind,
+    len: usize,
+) -> WktvStatus {
+    report((|| {
+        let runtime = unsafe { runtime_from_handle(runtime) }?;
+        if output.is_null() {
+            bail!(FfiError::NullPointer("output"));
+        }
+        let required = runtime.quant.len();
+        if len < required {
+            bail!(FfiError::BufferTooSmall { required, actual: len });
+        }
+        let output = unsafe { std::slice::from_raw_parts_mut(output, required) };
+        output.copy_from_slice(&runtime.quant);
+        Ok(())
+    })())
 }
@@ -0,0 +1,172 @@
+use std::ffi::c_char;
+
+use anyhow::{bail, Result};
+use web_rwkv::wgpu;
+
+use crate::{
+    error::FfiError,
+    layers_from_raw, path_from_raw,
+    quant::{quant_from_pairs, LayerQuant, QuantMap},
+};
+
+/// The only layout of [`LoadOptions`] understood by this version of the library.
+pub const LOAD_OPTIONS_VERSION: u32 = 1;
+
+/// Number of tokens run per dispatch unless [`LoadOptions::token_chunk_size`] says otherwise.
+pub const DEFAULT_TOKEN_CHUNK_SIZE: usize = 128;
+
+/// How a model file is laid out.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModelFormat {
+    /// Raw weights in safetensors, quantized while loading.
+    SafeTensors = 0,
+    /// A cbor prefab, already quantized.
+    Prefab = 1,
+}
+
+impl TryFrom<u32> for ModelFormat {
+    type Error = FfiError;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(ModelFormat::SafeTensors),
+            1 => Ok(ModelFormat::Prefab),
+            _ => Err(FfiError::InvalidArgument("unknown model format")),
+        }
+    }
+}
+
+/// Float type of the activations and of the non-quantized weights.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precision {
+    F32 = 0,
+    F16 = 1,
+}
+
+impl TryFrom<u32> for Precision {
+    type Error = FfiError;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Precision::F32),
+            1 => Ok(Precision::F16),
+            _ => Err(FfiError::InvalidArgument("unknown precision")),
+        }
+    }
+}
+
+/// Which GPU to prefer when no adapter index is given.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerPreference {
+    HighPerformance = 0,
+    LowPower = 1,
+}
+
+impl TryFrom<u32> for PowerPreference {
+    type Error = FfiError;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(PowerPreference::HighPerformance),
+            1 => Ok(PowerPreference::LowPower),
+            _ => Err(FfiError::InvalidArgument("unknown power preference")),
+        }
+    }
+}
+
+/// Everything a loader can be told, in one versioned struct.
+///
+/// `version` must be set to [`LOAD_OPTIONS_VERSION`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct LoadOptions {
+    pub version: u32,
+    pub path: *const c_char,
+    /// One of [`ModelFormat`].
+    pub format: u32,
+    /// Quantization of each listed layer; may be null if `quant_len` is 0. Must be empty for prefabs.
+    pub quant: *const LayerQuant,
+    pub quant_len: usize,
+    /// Rescale the activations every `rescale` layers; 0 keeps the model's default. Must be 0 for prefabs.
+    pub rescale: usize,
+    /// Install the extended hooks of v6 and v7 models, prefabs included; other versions fail with `InvalidArgument`.
+    pub extended: bool,
+    /// One of [`Precision`].
+    pub precision: u32,
+    pub num_batch: usize,
+    /// Number of tokens run per dispatch; 0 picks the default of 128.
+    pub token_chunk_size: usize,
+    /// One of [`PowerPreference`].
+    pub power_preference: u32,
+    /// Index of the GPU among all adapters found; negative to pick one by `power_preference`.
+    pub adapter_index: i32,
+}
+
+/// The options of a load, owned by the library.
+#[derive(Debug, Clone)]
+pub struct RuntimeOptions {
+    pub format: ModelFormat,
+    pub quant: QuantMap,
+    pub rescale: Option<usize>,
+    pub extended: bool,
+    pub fp16: bool,
+    pub num_batch: usize,
+    pub token_chunk_size: usize,
+    pub power_preference: wgpu::PowerPreference,
+    pub adapter_index: Option<usize>,
+}
+
+impl RuntimeOptions {
+    /// The options of the classic loaders: a single slot on the high-performance GPU, no quantization.
+    pub fn new(format: ModelFormat, fp16: bool) -> Self {
+        Self {
+            format,
+            quant: QuantMap::default(),
+            rescale: None,
+            extended: false,
+            fp16,
+            num_batch: 1,
+            token_chunk_size: DEFAULT_TOKEN_CHUNK_SIZE,
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            adapter_index: None,
+        }
+    }
+
+    /// Read the options and the model path out of a [`LoadOptions`].
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the pointers in `options` are valid as documented on [`LoadOptions`].
+    pub unsafe fn from_raw(options: &LoadOptions) -> Result<(Option<String>, Self)> {
+        if options.version != LOAD_OPTIONS_VERSION {
+            bail!(FfiError::InvalidArgument("unsupported load options version"));
+        }
+        let path = match options.path.is_null() {
+            true => None,
+            false => Some(unsafe { path_from_raw(options.path) }?),
+        };
+        let quant = quant_from_pairs(unsafe { layers_from_raw(options.quant, options.quant_len) }?)?;
+        let power_preference = match PowerPreference::try_from(options.power_preference)? {
+            PowerPreference::HighPerformance => wgpu::PowerPreference::HighPerformance,
+            PowerPreference::LowPower => wgpu::PowerPreference::LowPower,
+        };
+        let options = Self {
+            format: ModelFormat::try_from(options.format)?,
+            quant: QuantMap::Layers(quant),
+            rescale: (options.rescale > 0).then_some(options.rescale),
+            extended: options.extended,
+            fp16: Precision::try_from(options.precision)? == Precision::F16,
+            num_batch: options.num_batch,
+            token_chunk_size: match options.token_chunk_size {
+                0 => DEFAULT_TOKEN_CHUNK_SIZE,
+                size => size,
+            },
+            power_preference,
+            adapter_index: usize::try_from(options.adapter_index).ok(),
+        };
+        Ok((path, options))
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use web_rwkv::{
+    runtime::model::Quant,
+    tensor::matrix::{Float4Quant, Matrix},
+};
+
+use crate::error::FfiError;
+
+/// How the weights of a layer are stored on the GPU.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuantKind {
+    None = 0,
+    Int8 = 1,
+    NF4 = 2,
+    SF4 = 3,
+}
+
+impl TryFrom<u32> for QuantKind {
+    type Error = FfiError;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(QuantKind::None),
+            1 => Ok(QuantKind::Int8),
+            2 => Ok(QuantKind::NF4),
+            3 => Ok(QuantKind::SF4),
+            _ => Err(FfiError::InvalidArgument("unknown quantization kind")),
+        }
+    }
+}
+
+impl From<QuantKind> for Quant {
+    fn from(value: QuantKind) -> Self {
+        match value {
+            QuantKind::None => Quant::None,
+            QuantKind::Int8 => Quant::Int8,
+            QuantKind::NF4 => Quant::NF4,
+            QuantKind::SF4 => Quant::SF4,
+        }
+    }
+}
+
+/// The quantization a matrix is stored with.
+///
+/// NF4 and SF4 matrices share a layout and only differ in their lookup table, which is read back to tell them apart.
+pub fn matrix_quant(matrix: &Matrix) -> QuantKind {
+    match matrix {
+        Matrix::Fp16(_) => QuantKind::None,
+        Matrix::Int8 { .. } => QuantKind::Int8,
+        Matrix::Fp4 { q, .. } => match q.back_in_place().to_vec() == Float4Quant::default().0.to_vec() {
+            true => QuantKind::NF4,
+            false => QuantKind::SF4,
+        },
+    }
+}
+
+/// The quantization of one layer.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct LayerQuant {
+    pub layer: usize,
+    /// One of [`QuantKind`].
+    pub quant: u32,
+}
+
+/// Which layers of a model to quantize, as the loader was asked to.
+#[derive(Debug, Clone)]
+pub enum QuantMap {
+    /// The counts of the legacy loaders, applied by [`quant_from_counts`].
+    Counts { int8: usize, nf4: usize, sf4: usize },
+    /// An explicit map, whose layers must all exist in the model.
+    Layers(HashMap<usize, Quant>),
+}
+
+impl Default for QuantMap {
+    fn default() -> Self {
+        Self::Layers(HashMap::new())
+    }
+}
+
+impl QuantMap {
+    /// Whether no layer is quantized, whatever the model.
+    pub fn is_empty(&self) -> bool {
+        match self {
+            QuantMap::Counts { int8, nf4, sf4 } => *int8 == 0 && *nf4 == 0 && *sf4 == 0,
+            QuantMap::Layers(quant) => quant.is_empty(),
+        }
+    }
+
+    /// The quantization of each layer of a model with `num_layer` layers.
+    pub fn resolve(&self, num_layer: usize) -> Result<HashMap<usize, Quant>> {
+        match self {
+            &QuantMap::Counts { int8, nf4, sf4 } => Ok(quant_from_counts(int8, nf4, sf4, num_layer)),
+            QuantMap::Layers(quant) if quant.keys().any(|&layer| layer >= num_layer) => {
+                bail!(FfiError::InvalidArgument("quantized layer out of range"))
+            }
+            QuantMap::Layers(quant) => Ok(quant.clone()),
+        }
+    }
+}
+
+/// The quant map of the legacy loaders: the first `int8` layers in Int8, then the first `nf4` in NF4,
+/// then the first `sf4` in SF4, each overriding the ones before.
+///
+/// Counts past `num_layer` cover every layer, as callers pass a large count to quantize the whole model.
+pub fn quant_from_counts(int8: usize, nf4: usize, sf4: usize, num_layer: usize) -> HashMap<usize, Quant> {
+    (0..int8.min(num_layer))
+        .map(|layer| (layer, Quant::Int8))
+        .chain((0..nf4.min(num_layer)).map(|layer| (layer, Quant::NF4)))
+        .chain((0..sf4.min(num_layer)).map(|layer| (layer, Quant::SF4)))
+        .collect()
+}
+
+/// Later pairs for the same layer override earlier ones.
+pub fn quant_from_pairs(layers: &[LayerQuant]) -> Result<HashMap<usize, Quant>> {
+    let quant = layers
+        .iter()
+        .map(|layer| Ok((layer.layer, QuantKind::try_from(layer.quant)?.into())))
+        .collect::<Result<_, FfiError>>()?;
+    Ok(quant)
+}
+
+/// No model comes close to this many layers; a spec reaching past it is rejected before its ranges are expanded.
+const MAX_SPEC_LAYERS: usize = 1 << 12;
+
+/// Parse a quant spec such as `0-3:none,4-20:nf4,21-23:int8`.
+///
+/// Each comma-separated item gives a layer or an inclusive range of layers and a quantization,
+/// one of `none`, `int8`, `nf4` or `sf4`. Later items override earlier ones; unlisted layers are not quantized.
+pub fn parse_quant_spec(spec: &str) -> Result<HashMap<usize, Quant>> {
+    let mut quant = HashMap::new();
+    for item in spec.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+        let invalid = || FfiError::InvalidQuantSpec(item.to_string());
+        let (layers, kind) = item.split_once(':').ok_or_else(invalid)?;
+        let kind = match kind.trim().to_ascii_lowercase().as_str() {
+            "none" => Quant::None,
+            "int8" => Quant::Int8,
+            "nf4" => Quant::NF4,
+            "sf4" => Quant::SF4,
+            _ => bail!(invalid()),
+        };
+        let layer = |layer: &str| layer.trim().parse::<usize>().map_err(|_| invalid());
+        let (start, end) = match layers.split_once('-') {
+            Some((start, end)) => (layer(start)?, layer(end)?),
+            None => (layer(layers)?, layer(layers)?),
+        };
+        if start > end || end >= MAX_SPEC_LAYERS {
+            bail!(invalid());
+        }
+        quant.extend((start..=end).map(|layer| (layer, kind)));
+    }
+    Ok(quant)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spec_ranges_and_overrides() {
+        let quant = parse_quant_spec("0-3:none, 2-4:NF4,4:int8,,").unwrap();
+        let expected = [
+            (0, Quant::None),
+            (1, Quant::None),
+            (2, Quant::NF4),
+            (3, Quant::NF4),
+            (4, Quant::Int8),
+        ];
+        assert_eq!(quant.len(), expected.len());
+        for (layer, kind) in expected {
+            assert_eq!(quant[&layer], kind);
+        }
+        assert!(parse_quant_spec("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_specs_are_rejected() {
+        for spec in ["0-3", "0-3:fp8", "3-0:int8", "a:int8", "0-18446744073709551615:int8"] {
+            assert!(parse_quant_spec(spec).is_err(), "{spec}");
+        }
+    }
+
+    #[test]
+    fn counts_cover_at_most_every_layer() {
+        let quant = QuantMap::Counts { int8: usize::MAX, nf4: 2, sf4: 0 }.resolve(4).unwrap();
+        let expected = [(0, Quant::NF4), (1, Quant::NF4), (2, Quant::Int8), (3, Quant::Int8)];
+        assert_eq!(quant.len(), expected.len());
+        for (layer, kind) in expected {
+            assert_eq!(quant[&layer], kind);
+        }
+        assert!(QuantMap::Layers(quant).resolve(3).is_err());
+    }
+
+    #[test]
+    fn unknown_quant_kinds_are_rejected() {
+        let layers = [LayerQuant { layer: 0, quant: 1 }, LayerQuant { layer: 1, quant: 3 }];
+        let quant = quant_from_pairs(&layers).unwrap();
+        assert_eq!(quant[&0], Quant::Int8);
+        assert_eq!(quant[&1], Quant::SF4);
+        assert!(quant_from_pairs(&[LayerQuant { layer: 0, quant: 4 }]).is_err());
+    }
+}
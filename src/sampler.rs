@@ -0,0 +1,192 @@
+use anyhow::{bail, Result};
+use fastrand::Rng;
+use itertools::Itertools;
+
+use crate::{error::FfiError, Sampler};
+
+/// The only layout of [`SamplerConfig`] understood by this version of the library.
+pub const SAMPLER_CONFIG_VERSION: u32 = 1;
+
+/// Which truncation a [`SamplerConfig`] applies before drawing a token.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SamplerMode {
+    /// Keep the `top_k` most likely tokens, then cut off at cumulative probability `top_p`. Greedy if `top_k <= 1`.
+    Nucleus = 0,
+    /// Keep tokens at least `min_p` times as likely as the most likely one.
+    MinP = 1,
+    /// Keep the tokens whose surprise is closest to the entropy, up to cumulative probability `typical_p`.
+    Typical = 2,
+    /// Keep tokens with probability at least `top_a` times the square of the highest probability.
+    TopA = 3,
+    /// Mirostat v2: keep tokens with surprise below `mu`, and steer `mu` so the observed surprise tracks `mirostat_tau`.
+    Mirostat = 4,
+}
+
+impl TryFrom<u32> for SamplerMode {
+    type Error = FfiError;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(SamplerMode::Nucleus),
+            1 => Ok(SamplerMode::MinP),
+            2 => Ok(SamplerMode::Typical),
+            3 => Ok(SamplerMode::TopA),
+            4 => Ok(SamplerMode::Mirostat),
+            _ => Err(FfiError::InvalidArgument("unknown sampler mode")),
+        }
+    }
+}
+
+/// Sampling parameters that supersede [`Sampler`].
+///
+/// `version` must be set to [`SAMPLER_CONFIG_VERSION`]. Fields not used by `mode` are ignored.
+/// `temp` is applied to the kept tokens in every mode.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SamplerConfig {
+    pub version: u32,
+    /// One of [`SamplerMode`].
+    pub mode: u32,
+    pub temp: f32,
+    pub top_p: f32,
+    pub top_k: usize,
+    pub min_p: f32,
+    pub typical_p: f32,
+    pub top_a: f32,
+    pub mirostat_tau: f32,
+    pub mirostat_eta: f32,
+}
+
+impl From<Sampler> for SamplerConfig {
+    fn from(value: Sampler) -> Self {
+        Self {
+            version: SAMPLER_CONFIG_VERSION,
+            mode: SamplerMode::Nucleus as u32,
+            temp: value.temp,
+            top_p: value.top_p,
+            top_k: value.top_k,
+            min_p: 0.0,
+            typical_p: 1.0,
+            top_a: 0.0,
+            mirostat_tau: 5.0,
+            mirostat_eta: 0.1,
+        }
+    }
+}
+
+impl SamplerConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.version != SAMPLER_CONFIG_VERSION {
+            bail!(FfiError::InvalidArgument("unsupported sampler config version"));
+        }
+        if !self.is_greedy() && self.temp <= 0.0 {
+            bail!(FfiError::InvalidArgument("temperature must be positive"));
+        }
+        if !(0.0..=1.0).contains(&self.min_p) || !(0.0..=1.0).contains(&self.top_a) {
+            bail!(FfiError::InvalidArgument("`min_p` and `top_a` must be within [0, 1]"));
+        }
+        match SamplerMode::try_from(self.mode)? {
+            SamplerMode::Typical if !(self.typical_p > 0.0 && self.typical_p <= 1.0) => {
+                bail!(FfiError::InvalidArgument("`typical_p` must be within (0, 1]"))
+            }
+            SamplerMode::Mirostat if !(self.mirostat_tau > 0.0 && self.mirostat_tau.is_finite()) => {
+                bail!(FfiError::InvalidArgument("`mirostat_tau` must be positive"))
+            }
+            SamplerMode::Mirostat if !(self.mirostat_eta > 0.0 && self.mirostat_eta <= 1.0) => {
+                bail!(FfiError::InvalidArgument("`mirostat_eta` must be within (0, 1]"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The mode of a validated config.
+    fn mode(&self) -> SamplerMode {
+        SamplerMode::try_from(self.mode).unwrap_or(SamplerMode::Nucleus)
+    }
+
+    /// Whether this config always picks the most likely token, so the probabilities need not be computed.
+    pub fn is_greedy(&self) -> bool {
+        self.mode() == SamplerMode::Nucleus && self.top_k <= 1
+    }
+
+    /// Draw a token from the probabilities.
+    ///
+    /// `mu` is the running target surprise of mirostat; it is initialized to `2 * tau` when `None`.
+    pub fn sample(&self, probs: &[f32], mu: &mut Option<f32>, rng: &mut Rng) -> u32 {
+        let sampler = Sampler {
+            temp: self.temp,
+            top_p: self.top_p,
+            top_k: self.top_k,
+        };
+        let max = probs.iter().copied().fold(0.0, f32::max);
+        match self.mode() {
+            SamplerMode::Nucleus => sampler.sample(probs, rng),
+            SamplerMode::MinP => {
+                let threshold = self.min_p * max;
+                self.pick(probs.iter().copied().enumerate().filter(|&(_, x)| x >= threshold), rng)
+            }
+            SamplerMode::TopA => {
+                let threshold = self.top_a * max * max;
+                self.pick(probs.iter().copied().enumerate().filter(|&(_, x)| x >= threshold), rng)
+            }
+            SamplerMode::Typical => {
+                let entropy: f32 = probs.iter().filter(|&&x| x > 0.0).map(|&x| -x * x.ln()).sum();
+                let candidates = probs
+                    .iter()
+                    .copied()
+                    .enumerate()
+                    .filter(|&(_, x)| x > 0.0)
+                    .sorted_unstable_by(|(_, x), (_, y)| {
+                        let x = (-x.ln() - entropy).abs();
+                        let y = (-y.ln() - entropy).abs();
+                        x.total_cmp(&y)
+                    })
+                    .scan(0.0, |cum, (id, x)| match *cum < self.typical_p {
+                        true => {
+                            *cum += x;
+                            Some((id, x))
+                        }
+                        false => None,
+                    });
+                self.pick(candidates, rng)
+            }
+            SamplerMode::Mirostat => {
+                let tau = self.mirostat_tau;
+                let threshold = mu.get_or_insert(2.0 * tau);
+                let candidates = probs
+                    .iter()
+                    .copied()
+                    .enumerate()
+                    .sorted_unstable_by(|(_, x), (_, y)| x.total_cmp(y).reverse())
+                    .enumerate()
+                    .take_while(|&(rank, (_, x))| rank == 0 || -x.log2() <= *threshold)
+                    .map(|(_, candidate)| candidate);
+                let token = self.pick(candidates, rng);
+                let surprise = -probs[token as usize].log2();
+                *threshold -= self.mirostat_eta * (surprise - tau);
+                token
+            }
+        }
+    }
+
+    /// Apply the temperature to the kept tokens and draw one of them.
+    fn pick(&self, candidates: impl IntoIterator<Item = (usize, f32)>, rng: &mut Rng) -> u32 {
+        let candidates = candidates
+            .into_iter()
+            .map(|(id, x)| (id, x.powf(1.0 / self.temp)))
+            .collect_vec();
+        let sum: f32 = candidates.iter().map(|(_, x)| x).sum();
+        let rand = rng.f32() * sum;
+        let token = candidates
+            .iter()
+            .scan(0.0, |cum, &(id, x)| {
+                *cum += x;
+                Some((id, *cum))
+            })
+            .find_or_first(|&(_, cum)| rand <= cum)
+            .map(|(id, _)| id)
+            .unwrap_or_default();
+        token as u32
+    }
+}
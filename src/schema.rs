@@ -0,0 +1,313 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{bail, Result};
+use itertools::Itertools;
+use serde_json::Value;
+
+use crate::error::FfiError;
+
+/// Rules shared by every converted schema. Each value rule consumes the whitespace that follows it.
+const BASE_RULES: &str = r#"
+ws ::= [ \t\n]{0,8}
+value ::= object | array | string | number | boolean | null
+object ::= "{" ws ( string ":" ws value ( "," ws string ":" ws value )* )? "}" ws
+array ::= "[" ws ( value ( "," ws value )* )? "]" ws
+string ::= "\"" ( [^"\\\x00-\x1f] | "\\" ( ["\\/bfnrt] | "u" [0-9a-fA-F]{4} ) )* "\"" ws
+number ::= "-"? ( [0-9] | [1-9] [0-9]+ ) ( "." [0-9]+ )? ( [eE] [-+]? [0-9]+ )? ws
+integer ::= "-"? ( [0-9] | [1-9] [0-9]+ ) ws
+boolean ::= ( "true" | "false" ) ws
+null ::= "null" ws
+"#;
+
+/// Convert a JSON Schema into a GBNF grammar whose `root` accepts the JSON documents valid under it.
+///
+/// Supports `type`, `properties` and `required`, `items` with `minItems` and `maxItems`, `enum`, `const`,
+/// `anyOf`, `oneOf` and local `$ref`s. Other keywords are ignored, so the grammar may accept more than the schema.
+/// Object properties are emitted in the order the schema lists them.
+pub fn schema_to_gbnf(schema: &Value) -> Result<String> {
+    let mut converter = Converter {
+        document: schema,
+        rules: BTreeMap::new(),
+        refs: HashMap::new(),
+    };
+    let body = converter.visit(schema, "root-value")?;
+    converter.rules.insert("root".into(), format!("ws {body}"));
+    let rules = converter
+        .rules
+        .iter()
+        .map(|(name, body)| format!("{name} ::= {body}"))
+        .join("\n");
+    Ok(format!("{rules}\n{BASE_RULES}"))
+}
+
+struct Converter<'a> {
+    document: &'a Value,
+    rules: BTreeMap<String, String>,
+    /// The rule each resolved `$ref` was converted into.
+    refs: HashMap<String, String>,
+}
+
+/// Quote a string as a GBNF literal.
+fn literal(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\x{:02x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The GBNF for exactly this JSON value, followed by whitespace.
+fn json_literal(value: &Value) -> String {
+    format!("{} ws", literal(&value.to_string()))
+}
+
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c,
+            false => '-',
+        })
+        .collect()
+}
+
+impl Converter<'_> {
+    /// Claim a fresh rule name based on `base`, numbering it if taken.
+    ///
+    /// Distinct keys can sanitize to the same name, e.g. `a b` and `a-b`, and must not overwrite each other's rule.
+    fn reserve(&mut self, base: String) -> String {
+        let name = match self.rules.contains_key(&base) {
+            false => base,
+            true => (2..)
+                .map(|index| format!("{base}-{index}"))
+                .find(|name| !self.rules.contains_key(name))
+                .unwrap(),
+        };
+        self.rules.insert(name.clone(), String::new());
+        name
+    }
+
+    /// Returns a GBNF expression matching the schema, defining helper rules named after `name` as needed.
+    fn visit(&mut self, schema: &Value, name: &str) -> Result<String> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok("value".into()),
+            Value::Bool(false) => bail!(FfiError::InvalidGrammar(format!("`{name}` accepts nothing"))),
+            Value::Object(schema) => schema,
+            _ => bail!(FfiError::InvalidGrammar(format!("`{name}` is not a schema"))),
+        };
+
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            return self.visit_ref(reference);
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(json_literal(value));
+        }
+        if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+            return Ok(format!("( {} )", values.iter().map(json_literal).join(" | ")));
+        }
+        for key in ["anyOf", "oneOf"] {
+            if let Some(schemas) = schema.get(key).and_then(Value::as_array) {
+                let alts: Vec<_> = schemas
+                    .iter()
+                    .enumerate()
+                    .map(|(index, schema)| self.visit(schema, &format!("{name}-{index}")))
+                    .try_collect()?;
+                return Ok(format!("( {} )", alts.join(" | ")));
+            }
+        }
+
+        match schema.get("type") {
+            Some(Value::String(kind)) => self.visit_type(schema, kind, name),
+            Some(Value::Array(kinds)) => {
+                let alts: Vec<_> = kinds
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(|kind| self.visit_type(schema, kind, &format!("{name}-{kind}")))
+                    .try_collect()?;
+                Ok(format!("( {} )", alts.join(" | ")))
+            }
+            _ => Ok("value".into()),
+        }
+    }
+
+    fn visit_ref(&mut self, reference: &str) -> Result<String> {
+        if let Some(name) = self.refs.get(reference) {
+            return Ok(name.clone());
+        }
+        let Some(schema) = reference.strip_prefix('#').and_then(|pointer| self.document.pointer(pointer)) else {
+            bail!(FfiError::InvalidGrammar(format!("unresolved reference `{reference}`")));
+        };
+        // Record the name first so that recursive references terminate.
+        let name = self.reserve(format!("ref{}", sanitize(reference.trim_start_matches('#'))));
+        self.refs.insert(reference.to_string(), name.clone());
+        let body = self.visit(schema, &name)?;
+        self.rules.insert(name.clone(), body);
+        Ok(name)
+    }
+
+    fn visit_type(&mut self, schema: &serde_json::Map<String, Value>, kind: &str, name: &str) -> Result<String> {
+        match kind {
+            "object" => self.visit_object(schema, name),
+            "array" => self.visit_array(schema, name),
+            "string" | "number" | "integer" | "boolean" | "null" => Ok(kind.into()),
+            _ => bail!(FfiError::InvalidGrammar(format!("unknown type `{kind}`"))),
+        }
+    }
+
+    fn visit_object(&mut self, schema: &serde_json::Map<String, Value>, name: &str) -> Result<String> {
+        let Some(properties) = schema.get("properties").and_then(Value::as_object) else {
+            return Ok("object".into());
+        };
+        let required = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|required| required.iter().filter_map(Value::as_str).collect_vec())
+            .unwrap_or_default();
+        if let Some(key) = required.iter().find(|&&key| !properties.contains_key(key)) {
+            bail!(FfiError::InvalidGrammar(format!(
+                "`{name}` requires `{key}`, which is not among its properties"
+            )));
+        }
+
+        let mut members = vec![];
+        for (key, property) in properties {
+            let rule = self.reserve(format!("{name}-{}", sanitize(key)));
+            let body = self.visit(property, &rule)?;
+            self.rules.insert(rule.clone(), body);
+            let member = format!("{} ws \":\" ws {rule}", literal(&Value::from(key.as_str()).to_string()));
+            members.push((member, required.contains(&key.as_str())));
+        }
+
+        // Members after the first are each preceded by a comma; optional ones may be skipped.
+        fn rest(members: &[(String, bool)]) -> String {
+            match members {
+                [] => String::new(),
+                [(member, true), tail @ ..] => format!("\",\" ws {member} {}", rest(tail)),
+                [(member, false), tail @ ..] => format!("( \",\" ws {member} )? {}", rest(tail)),
+            }
+        }
+        fn first(members: &[(String, bool)]) -> String {
+            match members {
+                [] => String::new(),
+                [(member, true), tail @ ..] => format!("{member} {}", rest(tail)),
+                [(member, false), tail @ ..] => format!("( {member} {} | {} )", rest(tail), first(tail)),
+            }
+        }
+        Ok(format!("\"{{\" ws {} \"}}\" ws", first(&members)))
+    }
+
+    fn visit_array(&mut self, schema: &serde_json::Map<String, Value>, name: &str) -> Result<String> {
+        let item = match schema.get("items") {
+            Some(items) => {
+                let rule = self.reserve(format!("{name}-item"));
+                let body = self.visit(items, &rule)?;
+                self.rules.insert(rule.clone(), body);
+                rule
+            }
+            None => "value".into(),
+        };
+        let min = schema.get("minItems").and_then(Value::as_u64).unwrap_or(0) as usize;
+        let max = schema.get("maxItems").and_then(Value::as_u64).map(|max| max as usize);
+        if let Some(max) = max.filter(|&max| max < min) {
+            bail!(FfiError::InvalidGrammar(format!("`{name}` has `minItems` {min} above `maxItems` {max}")));
+        }
+        let items = match (min, max) {
+            (_, Some(0)) => String::new(),
+            (0, None) => format!("( {item} ( \",\" ws {item} )* )?"),
+            (0, Some(max)) => format!("( {item} ( \",\" ws {item} ){{0,{}}} )?", max - 1),
+            (min, None) => format!("{item} ( \",\" ws {item} ){{{},}}", min - 1),
+            (min, Some(max)) => format!("{item} ( \",\" ws {item} ){{{},{}}}", min - 1, max - 1),
+        };
+        Ok(format!("\"[\" ws {items} \"]\" ws"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::grammar::{Grammar, Vocab};
+
+    /// Whether the grammar of the schema accepts the text, fed through a vocabulary of single bytes.
+    fn accepts(schema: &Value, text: &str) -> bool {
+        let grammar = Grammar::from_gbnf(&schema_to_gbnf(schema).unwrap()).unwrap();
+        let tokens = (0..=255u8).map(|byte| vec![byte]).collect_vec();
+        let vocab = Vocab::new(&tokens);
+        text.bytes()
+            .try_fold(grammar.start(), |state, byte| grammar.advance(&state, byte as u32, &vocab).ok())
+            .is_some_and(|state| grammar.is_accepting(&state))
+    }
+
+    #[test]
+    fn optional_properties() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "age": { "type": "integer" },
+                "tags": { "type": "array", "items": { "enum": ["a", "b"] }, "maxItems": 2 }
+            },
+            "required": ["age"]
+        });
+        assert!(accepts(&schema, r#"{"age": 3}"#));
+        assert!(accepts(&schema, r#"{"name": "x", "age": -1, "tags": ["a", "b"]}"#));
+        assert!(accepts(&schema, r#"{ "age": 1, "tags": [] }"#));
+        assert!(!accepts(&schema, r#"{"name": "x"}"#));
+        assert!(!accepts(&schema, r#"{"age": 1.5}"#));
+        assert!(!accepts(&schema, r#"{"age": 1, "tags": ["a", "b", "a"]}"#));
+        assert!(!accepts(&schema, r#"{"age": 1, "name": "x"}"#));
+    }
+
+    #[test]
+    fn colliding_keys_keep_their_own_rules() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "a b": { "type": "integer" },
+                "a-b": { "type": "boolean" }
+            },
+            "required": ["a b", "a-b"]
+        });
+        assert!(accepts(&schema, r#"{"a b": 1, "a-b": true}"#));
+        assert!(!accepts(&schema, r#"{"a b": true, "a-b": true}"#));
+        assert!(!accepts(&schema, r#"{"a b": 1, "a-b": 1}"#));
+    }
+
+    #[test]
+    fn references() {
+        let schema = json!({
+            "$defs": {
+                "node": {
+                    "type": "object",
+                    "properties": { "next": { "anyOf": [{ "$ref": "#/$defs/node" }, { "type": "null" }] } },
+                    "required": ["next"]
+                }
+            },
+            "$ref": "#/$defs/node"
+        });
+        assert!(accepts(&schema, r#"{"next": {"next": null}}"#));
+        assert!(!accepts(&schema, r#"{"next": 1}"#));
+    }
+
+    #[test]
+    fn inconsistent_schemas_are_rejected() {
+        for schema in [
+            json!({ "type": "array", "minItems": 3, "maxItems": 2 }),
+            json!({ "type": "object", "properties": { "a": true }, "required": ["b"] }),
+            json!({ "type": "unknown" }),
+            json!({ "$ref": "#/missing" }),
+            json!(false),
+        ] {
+            assert!(schema_to_gbnf(&schema).is_err(), "{schema}");
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use half::f16;
+use web_rwkv::{
+    runtime::model::{ModelInfo, ModelVersion},
+    tensor::TensorCpu,
+};
+
+use crate::error::FfiError;
+
+/// The layout of saved state files written by this version of the library.
+pub const STATE_FORMAT_VERSION: u32 = 1;
+
+/// How many embedding values are hashed into a fingerprint, spread evenly over the matrix.
+const FINGERPRINT_SAMPLES: usize = 1 << 16;
+
+/// Identify a model by its dimensions and a sample of its embedding matrix.
+///
+/// The embedding is never quantized, so the same weights give the same fingerprint whether they were loaded
+/// from safetensors or a prefab, in any precision.
+pub fn fingerprint(info: &ModelInfo, embed: &TensorCpu<f16>) -> u64 {
+    let fields = [info.num_layer, info.num_emb, info.num_hidden, info.num_vocab, info.num_head];
+    let values = embed.as_ref();
+    let step = values.len().div_ceil(FINGERPRINT_SAMPLES).max(1);
+    let bytes = fields
+        .into_iter()
+        .flat_map(|field| (field as u64).to_le_bytes())
+        .chain(values.iter().step_by(step).flat_map(|value| value.to_bits().to_le_bytes()));
+    bytes.fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// The metadata stored alongside a saved state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateHeader {
+    pub version: u32,
+    pub num_layer: usize,
+    pub num_emb: usize,
+    pub num_head: usize,
+    pub head_size: usize,
+    pub fingerprint: u64,
+    /// RNG state of the slot the state was saved from.
+    pub rng: u64,
+}
+
+impl StateHeader {
+    pub fn new(info: &ModelInfo, fingerprint: u64, rng: u64) -> Self {
+        let version = match info.version {
+            ModelVersion::V4 => 4,
+            ModelVersion::V5 => 5,
+            ModelVersion::V6 => 6,
+            ModelVersion::V7 => 7,
+        };
+        Self {
+            version,
+            num_layer: info.num_layer,
+            num_emb: info.num_emb,
+            num_head: info.num_head,
+            head_size: info.num_emb / info.num_head.max(1),
+            fingerprint,
+            rng,
+        }
+    }
+
+    pub fn to_metadata(&self) -> HashMap<String, String> {
+        [
+            ("format", STATE_FORMAT_VERSION.to_string()),
+            ("version", self.version.to_string()),
+            ("num_layer", self.num_layer.to_string()),
+            ("num_emb", self.num_emb.to_string()),
+            ("num_head", self.num_head.to_string()),
+            ("head_size", self.head_size.to_string()),
+            ("fingerprint", format!("{:016x}", self.fingerprint)),
+            ("rng", format!("{:016x}", self.rng)),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value))
+        .collect()
+    }
+
+    pub fn from_metadata(metadata: Option<&HashMap<String, String>>) -> Result<Self> {
+        let Some(metadata) = metadata else {
+            bail!(FfiError::InvalidStateFile("missing metadata".into()));
+        };
+        let field = |key: &str| match metadata.get(key) {
+            Some(value) => Ok(value.as_str()),
+            None => Err(FfiError::InvalidStateFile(format!("missing `{key}`"))),
+        };
+        let decimal = |key: &str| {
+            let value = field(key)?;
+            value
+                .parse::<usize>()
+                .map_err(|_| FfiError::InvalidStateFile(format!("bad `{key}`: {value}")))
+        };
+        let hex = |key: &str| {
+            let value = field(key)?;
+            u64::from_str_radix(value, 16).map_err(|_| FfiError::InvalidStateFile(format!("bad `{key}`: {value}")))
+        };
+
+        let format = decimal("format")?;
+        if format != STATE_FORMAT_VERSION as usize {
+            bail!(FfiError::InvalidStateFile(format!("unsupported format {format}")));
+        }
+        Ok(Self {
+            version: decimal("version")? as u32,
+            num_layer: decimal("num_layer")?,
+            num_emb: decimal("num_emb")?,
+            num_head: decimal("num_head")?,
+            head_size: decimal("head_size")?,
+            fingerprint: hex("fingerprint")?,
+            rng: hex("rng")?,
+        })
+    }
+
+    /// Fail unless a state with this header can be loaded into a model with header `model`.
+    pub fn check(&self, model: &Self) -> Result<()> {
+        let fields = [
+            ("version", self.version as u64, model.version as u64),
+            ("num_layer", self.num_layer as u64, model.num_layer as u64),
+            ("num_emb", self.num_emb as u64, model.num_emb as u64),
+            ("num_head", self.num_head as u64, model.num_head as u64),
+            ("head_size", self.head_size as u64, model.head_size as u64),
+        ];
+        for (name, state, expected) in fields {
+            if state != expected {
+                bail!(FfiError::IncompatibleState(format!(
+                    "state has {name} {state}, model has {expected}"
+                )));
+            }
+        }
+        if self.fingerprint != model.fingerprint {
+            bail!(FfiError::IncompatibleState("state was saved from different weights".into()));
+        }
+        Ok(())
+    }
+}
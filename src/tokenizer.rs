@@ -0,0 +1,285 @@
+use std::{
+    ffi::{c_char, CStr},
+    path::Path,
+    sync::{Arc, OnceLock, RwLock},
+};
+
+use anyhow::{bail, Result};
+use web_rwkv::tokenizer::Tokenizer;
+
+use crate::{
+    error::{report, FfiError, WktvStatus},
+    grammar::Vocab,
+    path_from_raw, tokens_from_raw, write_output,
+};
+
+static TOKENIZER: RwLock<Option<Arc<WktvTokenizer>>> = RwLock::new(None);
+
+/// A loaded vocabulary.
+///
+/// This is opaque to C; handles to it are created by `load_tokenizer_create` and freed by `release_tokenizer_handle`.
+pub struct WktvTokenizer {
+    tokenizer: Tokenizer,
+    vocab: OnceLock<Arc<Vocab>>,
+}
+
+impl WktvTokenizer {
+    fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        let tokenizer = Tokenizer::new(&contents)?;
+        let vocab = OnceLock::new();
+        Ok(Self { tokenizer, vocab })
+    }
+
+    pub fn encode(&self, text: &[u8]) -> Result<Vec<u32>> {
+        Ok(self.tokenizer.encode(text)?)
+    }
+
+    pub fn decode(&self, tokens: &[u32]) -> Result<Vec<u8>> {
+        Ok(self.tokenizer.decode(tokens)?)
+    }
+
+    /// The vocabulary arranged for grammar matching, built on first use.
+    pub fn vocab(&self) -> Arc<Vocab> {
+        let vocab = self
+            .vocab
+            .get_or_init(|| Arc::new(Vocab::new(self.tokenizer.token_index_to_bytes())));
+        vocab.clone()
+    }
+}
+
+/// Holds back the bytes of a UTF-8 sequence that is split across tokens until it is complete.
+#[derive(Debug, Default, Clone)]
+pub struct Utf8Buffer(Vec<u8>);
+
+impl Utf8Buffer {
+    /// Append the bytes of newly decoded tokens, and take out everything up to the last complete character.
+    ///
+    /// Bytes that can never form a valid character are passed through as they are.
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<u8> {
+        self.0.extend_from_slice(bytes);
+        let len = self.0.len();
+        let start = self
+            .0
+            .iter()
+            .rposition(|&x| x & 0xc0 != 0x80)
+            .filter(|&start| len - start < 4);
+        let split = match start {
+            Some(start) => match std::str::from_utf8(&self.0[start..]) {
+                Err(err) if err.error_len().is_none() => start,
+                _ => len,
+            },
+            None => len,
+        };
+        let rest = self.0.split_off(split);
+        std::mem::replace(&mut self.0, rest)
+    }
+
+    /// Append the bytes and take out everything held, even if it ends in the middle of a character.
+    pub fn flush(&mut self, bytes: &[u8]) -> Vec<u8> {
+        self.0.extend_from_slice(bytes);
+        std::mem::take(&mut self.0)
+    }
+}
+
+/// Get the global tokenizer.
+pub fn global_tokenizer() -> Result<Arc<WktvTokenizer>> {
+    let tokenizer = TOKENIZER.read().unwrap().clone();
+    tokenizer.ok_or_else(|| FfiError::TokenizerNotLoaded.into())
+}
+
+/// Borrow the tokenizer behind a handle.
+///
+/// # Safety
+///
+/// The caller must ensure that `tokenizer` is either null or a live handle returned by `load_tokenizer_create`.
+pub unsafe fn tokenizer_from_handle<'a>(tokenizer: *const WktvTokenizer) -> Result<&'a WktvTokenizer> {
+    let tokenizer = unsafe { tokenizer.as_ref() };
+    tokenizer.ok_or_else(|| FfiError::NullHandle.into())
+}
+
+/// Read the bytes of a C string.
+///
+/// # Safety
+///
+/// The caller must ensure that `text` is either null or a valid C string.
+pub unsafe fn text_from_raw<'a>(text: *const c_char) -> Result<&'a [u8]> {
+    if text.is_null() {
+        bail!(FfiError::NullPointer("text"));
+    }
+    Ok(unsafe { CStr::from_ptr(text).to_bytes() })
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenOutput {
+    pub len: usize,
+    pub data: *mut u32,
+}
+
+impl From<Vec<u32>> for TokenOutput {
+    fn from(value: Vec<u32>) -> Self {
+        let len = value.len();
+        let data = Box::into_raw(value.into_boxed_slice()) as *mut u32;
+        TokenOutput { len, data }
+    }
+}
+
+/// UTF-8 bytes produced by the text functions. The bytes are not null-terminated.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TextOutput {
+    pub len: usize,
+    pub data: *mut u8,
+}
+
+impl From<Vec<u8>> for TextOutput {
+    fn from(value: Vec<u8>) -> Self {
+        let len = value.len();
+        let data = Box::into_raw(value.into_boxed_slice()) as *mut u8;
+        TextOutput { len, data }
+    }
+}
+
+/// Load the global tokenizer from a vocabulary file, e.g. `rwkv_vocab_v20230424.json`.
+///
+/// # Safety
+///
+/// The caller must ensure that `path` is valid.
+#[no_mangle]
+pub unsafe extern "C" fn load_tokenizer(path: *const c_char) -> WktvStatus {
+    report((|| {
+        let path = unsafe { path_from_raw(path) }?;
+        let tokenizer = WktvTokenizer::load(path)?;
+        TOKENIZER.write().unwrap().replace(Arc::new(tokenizer));
+        Ok(())
+    })())
+}
+
+/// Load a tokenizer from a vocabulary file and write its handle into `tokenizer`.
+///
+/// # Safety
+///
+/// The caller must ensure that `path` is valid and `tokenizer` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn load_tokenizer_create(path: *const c_char, tokenizer: *mut *mut WktvTokenizer) -> WktvStatus {
+    report((|| {
+        if tokenizer.is_null() {
+            bail!(FfiError::NullPointer("tokenizer"));
+        }
+        let path = unsafe { path_from_raw(path) }?;
+        let handle = Box::into_raw(Box::new(WktvTokenizer::load(path)?));
+        unsafe { write_output(tokenizer, handle) }
+    })())
+}
+
+/// Release a tokenizer handle. The handle must not be used afterwards.
+///
+/// # Safety
+///
+/// The caller must ensure that `tokenizer` is a handle returned by `load_tokenizer_create` and not yet released.
+#[no_mangle]
+pub unsafe extern "C" fn release_tokenizer_handle(tokenizer: *mut WktvTokenizer) -> WktvStatus {
+    report((|| {
+        if tokenizer.is_null() {
+            bail!(FfiError::NullHandle);
+        }
+        let _ = unsafe { Box::from_raw(tokenizer) };
+        Ok(())
+    })())
+}
+
+/// Encode a null-terminated UTF-8 string into tokens with the global tokenizer.
+///
+/// # Safety
+///
+/// The caller must ensure that `text` is a valid C string and `output` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn tokenize(text: *const c_char, output: *mut TokenOutput) -> WktvStatus {
+    match global_tokenizer() {
+        Ok(tokenizer) => unsafe { tokenize_handle(&*tokenizer, text, output) },
+        Err(err) => report(Err(err)),
+    }
+}
+
+/// Encode a null-terminated UTF-8 string into tokens with a tokenizer handle.
+///
+/// # Safety
+///
+/// The caller must ensure that `tokenizer` is a live handle, `text` is a valid C string and `output` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn tokenize_handle(
+    tokenizer: *const WktvTokenizer,
+    text: *const c_char,
+    output: *mut TokenOutput,
+) -> WktvStatus {
+    report((|| {
+        let tokenizer = unsafe { tokenizer_from_handle(tokenizer) }?;
+        let text = unsafe { text_from_raw(text) }?;
+        let tokens = tokenizer.encode(text)?;
+        unsafe { write_output(output, tokens.into()) }
+    })())
+}
+
+/// Decode tokens into raw bytes with the global tokenizer.
+///
+/// The bytes may end in the middle of a UTF-8 sequence if the last token does.
+///
+/// # Safety
+///
+/// The caller must ensure that `tokens` is valid, `len` does not exceed the actual length of `tokens`,
+/// and `output` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn detokenize(tokens: *const u32, len: usize, output: *mut TextOutput) -> WktvStatus {
+    match global_tokenizer() {
+        Ok(tokenizer) => unsafe { detokenize_handle(&*tokenizer, tokens, len, output) },
+        Err(err) => report(Err(err)),
+    }
+}
+
+/// Decode tokens into raw bytes with a tokenizer handle.
+///
+/// # Safety
+///
+/// The caller must ensure that `tokenizer` is a live handle, `tokens` is valid,
+/// `len` does not exceed the actual length of `tokens`, and `output` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn detokenize_handle(
+    tokenizer: *const WktvTokenizer,
+    tokens: *const u32,
+    len: usize,
+    output: *mut TextOutput,
+) -> WktvStatus {
+    report((|| {
+        let tokenizer = unsafe { tokenizer_from_handle(tokenizer) }?;
+        let tokens = unsafe { tokens_from_raw(tokens, len) };
+        let text = tokenizer.decode(tokens)?;
+        unsafe { write_output(output, text.into()) }
+    })())
+}
+
+/// Delete the tokens created by `tokenize`.
+///
+/// # Safety
+///
+/// The caller must ensure that `output` was returned by `tokenize` and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn free_tokens(output: TokenOutput) {
+    if !output.data.is_null() {
+        let data = std::ptr::slice_from_raw_parts_mut(output.data, output.len);
+        let _ = unsafe { Box::from_raw(data) };
+    }
+}
+
+/// Delete the bytes created by `detokenize` and the text inference functions.
+///
+/// # Safety
+///
+/// The caller must ensure that `output` was returned by one of these functions and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn free_text(output: TextOutput) {
+    if !output.data.is_null() {
+        let data = std::ptr::slice_from_raw_parts_mut(output.data, output.len);
+        let _ = unsafe { Box::from_raw(data) };
+    }
+}